@@ -6,6 +6,8 @@ use clap::Parser;
 use futures::StreamExt;
 use tokio::{net::TcpStream, sync::oneshot};
 
+use nats_sans_io::{NatsBinding, ReconnectOptions};
+
 use nats_client::tokio::NatsOverTcp;
 
 #[derive(Parser)]
@@ -40,7 +42,9 @@ async fn main() {
             pong_delay: Duration::from_secs(0),
             keep_alive: Duration::from_secs(30),
         };
-        let _ = protocol.run(timeouts, send).await.unwrap();
+        let binding = NatsBinding::new(timeouts).with_reconnect(ReconnectOptions::default());
+        let reason = protocol.run(binding, send).await;
+        log::info!("Connection ended: {reason}");
     });
 
     let client = recv.await.unwrap();
@@ -60,16 +64,15 @@ async fn main() {
         tokio_stream::iter(timer).boxed()
     };
 
-
     let mut interval = tokio::time::interval(Duration::from_secs(1));
     while let Some(timestamp) = stream.next().await {
         interval.tick().await;
         println!("{timestamp}");
-        client.publish(subject.clone(), timestamp.into()).await;
+        if let Err(e) = client.publish(subject.clone(), timestamp.into()).await {
+            log::error!("Failed to publish: {e}");
+        }
     }
 
-    tokio::time::sleep(Duration::from_secs(1)).await;
-    client.close().await;
-
-    conn_task.abort();
+    client.close().await.expect("Failed to close connection");
+    conn_task.await.unwrap();
 }
@@ -6,6 +6,8 @@ use clap::Parser;
 use futures::StreamExt;
 use tokio::{net::TcpStream, sync::oneshot};
 
+use nats_sans_io::{NatsBinding, ReconnectOptions};
+
 use nats_client::tokio::{NatsOverTcp, SubscriptionOptions};
 
 #[derive(Parser)]
@@ -42,16 +44,22 @@ async fn main() {
             pong_delay: Duration::from_secs(0),
             keep_alive: Duration::from_secs(30),
         };
-        let _ = protocol.run(timeouts, send).await.unwrap();
+        let binding = NatsBinding::new(timeouts).with_reconnect(ReconnectOptions::default());
+        let reason = protocol.run(binding, send).await;
+        log::info!("Connection ended: {reason}");
     });
 
     let client = recv.await.unwrap();
     let options = SubscriptionOptions {
         max_msgs,
         queue_group: queue_group.clone(),
+        ..Default::default()
     };
 
-    let mut subscriber = client.subscribe(subject.clone(), options).await;
+    let mut subscriber = client
+        .subscribe(subject.clone(), options)
+        .await
+        .expect("Failed to subscribe");
     while let Some(message) = subscriber.next().await {
         println!("{message:?}");
     }
@@ -1,156 +1,842 @@
-use std::time::{Duration, Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    future::Future,
+    pin::Pin,
+    time::{Duration, Instant},
+};
 
 use futures::{SinkExt, StreamExt};
-use nats_sans_io::{NatsBinding, SubscribeResponse};
+use nats_sans_io::{
+    AckError, AckId, ClientResponse, CommandError, ConnectionCommand, Event, FlushId, NatsBinding,
+    NatsProtocolError, PublishOptions, RequestError, RequestId, RequestResponse, ServerAddr, Sid,
+    Statistics, SubscribeResponse,
+};
 use tokio::{
     io::{BufReader, BufWriter},
-    net::TcpStream,
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
     sync::{mpsc, oneshot},
     time,
 };
-use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
 
 use super::{Subscriber, SubscriptionOptions};
 
+#[derive(thiserror::Error, Clone, Debug)]
+pub enum NatsError {
+    #[error("Connection to the server was lost")]
+    ConnectionLost,
+
+    #[error("Server violated the protocol: {0}")]
+    Protocol(String),
+
+    #[error("Server rejected the credentials: {0}")]
+    Authorization(String),
+
+    #[error("Server rejected the connection: {0}")]
+    Rejected(String),
+
+    #[error("Connection is closed")]
+    Closed,
+
+    #[error(transparent)]
+    Command(#[from] CommandError),
+
+    #[error(transparent)]
+    Request(#[from] RequestError),
+
+    #[error(transparent)]
+    Acknowledgement(#[from] AckError),
+
+    #[error("Server did not answer in time")]
+    TimedOut,
+}
+
+impl From<NatsProtocolError> for NatsError {
+    fn from(error: NatsProtocolError) -> Self {
+        match error {
+            NatsProtocolError::NotInfoReceived | NatsProtocolError::EchoNotSupported => {
+                Self::Protocol(error.to_string())
+            }
+            NatsProtocolError::ConnectRejected(error) => Self::Rejected(error),
+            NatsProtocolError::AuthorizationFailed(error) => Self::Authorization(error),
+            NatsProtocolError::ConnectionLost => Self::ConnectionLost,
+        }
+    }
+}
+
+/// What a [UserHandle] asks of the task running the connection.
 #[derive(Debug)]
-pub enum NatsError {}
+pub(crate) enum UserCommand {
+    Subscribe {
+        subject: String,
+        options: SubscriptionOptions,
+        responder: oneshot::Sender<
+            Result<(SubscribeResponse, mpsc::Receiver<nats_codec::Message>), CommandError>,
+        >,
+    },
+    /// Delivers the messages of the new subscription into the channel of `sid`.
+    Resubscribe {
+        sid: Sid,
+        subject: String,
+        options: SubscriptionOptions,
+        responder: oneshot::Sender<Result<SubscribeResponse, CommandError>>,
+    },
+    Publish {
+        command: ConnectionCommand,
+        responder: oneshot::Sender<Result<(), CommandError>>,
+    },
+    Request {
+        command: ConnectionCommand,
+        responder: oneshot::Sender<RequestResponse>,
+    },
+    Flush {
+        responder: oneshot::Sender<Result<Duration, CommandError>>,
+    },
+    /// A publish awaiting the server's acknowledgement.
+    Acknowledge {
+        command: ConnectionCommand,
+        responder: oneshot::Sender<Result<(), AckError>>,
+    },
+    ServerInfo {
+        responder: oneshot::Sender<Option<nats_codec::Info>>,
+    },
+    Statistics {
+        responder: oneshot::Sender<Statistics>,
+    },
+    /// Requires no answer.
+    Forward(ConnectionCommand),
+    /// Closes or drains the connection, and awaits why it ended.
+    ShutDown {
+        command: ConnectionCommand,
+        responder: oneshot::Sender<NatsError>,
+    },
+}
+
+type Reader = FramedRead<BufReader<OwnedReadHalf>, nats_codec::ServerCodec>;
+type Writer = FramedWrite<BufWriter<OwnedWriteHalf>, nats_codec::ClientCodec>;
 
 pub struct NatsOverTcp {
-    conn: TcpStream,
+    conn: Option<TcpStream>,
 }
 
 impl NatsOverTcp {
     pub fn new(tcp: TcpStream) -> Self {
-        Self { conn: tcp }
+        Self { conn: Some(tcp) }
+    }
+
+    /// Connects to the servers of the binding's pool, see [NatsBinding::with_servers].
+    pub fn unconnected() -> Self {
+        Self { conn: None }
     }
 
+    /// Hands out a [UserHandle] through `chan` once the server has accepted the connection.
+    /// Returns why the connection ended: [NatsError::Closed] if it was closed on purpose,
+    /// see [UserHandle::close] and [UserHandle::drain], or e.g. that the server rejected the initial connection.
     pub async fn run(
         self,
-        timeouts: nats_sans_io::Timeouts,
+        mut binding: NatsBinding,
         chan: oneshot::Sender<UserHandle>,
-    ) -> Result<(), NatsError> {
+    ) -> NatsError {
         let Self { conn: tcp } = self;
-        let (reader, writer) = tcp.into_split();
 
-        let (mut reader, mut writer) = (
-            FramedRead::new(BufReader::new(reader), nats_codec::ServerCodec),
-            FramedWrite::new(BufWriter::new(writer), nats_codec::ClientCodec),
-        );
+        // Reconnect to wherever the initial connection was made to
+        if let Some(address) = tcp.as_ref().and_then(|tcp| tcp.peer_addr().ok()) {
+            binding.add_server(address.into());
+        }
+        let mut transport = tcp.map(framed);
+        // Established alongside handling commands, so that they are not held up by an unreachable server
+        let mut connecting: Option<Connecting> = None;
+        if transport.is_some() {
+            binding.handle_connected(Instant::now());
+        }
 
-        let mut send_ping_ticker = time::interval(Duration::from_secs(5));
-        let mut recv_ping_ticker = time::interval(Duration::from_secs(5));
-        let mut recv_pong_ticker = time::interval(Duration::from_secs(5));
+        let (sender, mut receiver) = mpsc::channel(1024 * 1024);
+        let mut chan = Some(chan);
 
-        let mut binding = NatsBinding::new(timeouts);
+        let mut subscribers: HashMap<Sid, mpsc::Sender<nats_codec::Message>> = HashMap::new();
+        // Subscriptions the binding has ended, whose subscribers may still have messages pending
+        let mut ended: HashSet<Sid> = HashSet::new();
+        let mut requests: HashMap<RequestId, oneshot::Sender<RequestResponse>> = HashMap::new();
+        let mut flushes: HashMap<FlushId, oneshot::Sender<Result<Duration, CommandError>>> =
+            HashMap::new();
+        let mut acks: HashMap<AckId, oneshot::Sender<Result<(), AckError>>> = HashMap::new();
+        // Those awaiting the connection to end, see [UserHandle::close]
+        let mut closers: Vec<oneshot::Sender<NatsError>> = vec![];
+        // The server closes the connection after rejecting the credentials
+        let mut authorization_error = None;
+        let mut rejection = None;
 
-        let (sender, mut receiver) = mpsc::channel(1024 * 1024);
-        chan.send(UserHandle {
-            chan: sender.clone(),
-        })
-        .unwrap();
+        let reason = 'running: loop {
+            while let Some((id, response)) = binding.poll_reply() {
+                if let Some(responder) = requests.remove(&id) {
+                    // The requester may have stopped waiting
+                    let _ = responder.send(response);
+                }
+            }
 
-        loop {
-            if let Some(transmit) = binding.poll_transmit() {
-                let _ = writer.send(transmit).await;
-                continue;
+            while let Some(event) = binding.poll_event() {
+                match event {
+                    Event::Connected => {
+                        log::info!("Connected to NATS Server");
+                        if let Some(chan) = chan.take() {
+                            // The caller may have stopped waiting
+                            let _ = chan.send(UserHandle {
+                                chan: sender.clone(),
+                            });
+                        }
+                    }
+                    Event::ConnectRejected { error } => {
+                        log::error!("NATS Server rejected the connection: {error}");
+                        rejection = Some(error);
+                    }
+                    Event::Disconnected => {
+                        log::warn!("Disconnected from NATS Server");
+                        transport = None;
+                    }
+                    Event::Reconnect { attempt, server } => {
+                        let timeout = binding.handshake_options().timeout;
+                        connecting = Some(Box::pin(reconnect(server, attempt, timeout)));
+                    }
+                    Event::ConnectionLost => {
+                        break 'running match (authorization_error, rejection) {
+                            (Some(error), _) => NatsError::Authorization(error),
+                            (None, Some(error)) => NatsError::Rejected(error),
+                            (None, None) => NatsError::ConnectionLost,
+                        };
+                    }
+                    Event::Closed => {
+                        if let Some((_, writer)) = &mut transport {
+                            close(&mut binding, writer).await;
+                        }
+                        log::info!("Closed connection to NATS Server");
+                        break 'running NatsError::Closed;
+                    }
+                    Event::ProtocolError { error } => break 'running NatsError::Protocol(error),
+                    Event::LameDuck => log::warn!("NATS Server is entering lame duck mode"),
+                    Event::AuthorizationFailed { error } => {
+                        log::error!("NATS Server rejected the credentials: {error}");
+                        authorization_error = Some(error);
+                    }
+                    Event::SlowConsumer { sid, dropped } => {
+                        log::warn!(
+                            "Subscriber {sid} is not keeping up, {dropped} messages dropped"
+                        );
+                    }
+                    // Its subscriber still receives the messages that were delivered beforehand
+                    Event::Unsubscribed { sid } => {
+                        ended.insert(sid);
+                    }
+                    Event::Flushed { id, rtt } => {
+                        if let Some(responder) = flushes.remove(&id) {
+                            let _ = responder.send(Ok(rtt));
+                        }
+                    }
+                    Event::PublishDiscarded { subject, error } => {
+                        log::warn!("Publish to {subject} was discarded: {error}");
+                    }
+                    Event::Acknowledged { id, result } => {
+                        if let Some(responder) = acks.remove(&id) {
+                            let _ = responder.send(result);
+                        }
+                    }
+                }
+            }
+
+            let full = forward(&mut binding, &mut subscribers, &mut ended);
+
+            if let Some((_, writer)) = &mut transport {
+                if let Err(e) = transmit(&mut binding, writer).await {
+                    log::error!("Failed to write to TCP Stream: {e:?}");
+                    binding.handle_disconnected(Instant::now());
+                    continue;
+                }
             }
 
-            tokio::select! {
-                // TODO: Make the handle_* functions error when connection has been lost so we can break here
-                time = send_ping_ticker.tick() => {
-                    let _ = binding.handle_send_ping_timeout(time.into());
+            let outcome = tokio::select! {
+                time = timeout(binding.poll_timeout()) => binding.handle_timeout(time),
+                connected = connect(&mut connecting) => {
+                    connecting = None;
+                    match connected {
+                        Some(connected) => {
+                            transport = Some(connected);
+                            binding.handle_connected(Instant::now());
+                        }
+                        None => binding.handle_connect_failed(Instant::now()),
+                    }
+                    Ok(())
+                }
+                () = room(full) => Ok(()),
+                Some(command) = receiver.recv() => dispatch(
+                    &mut binding,
+                    command,
+                    &mut subscribers,
+                    &mut requests,
+                    &mut flushes,
+                    &mut acks,
+                    &mut closers,
+                ),
+                command = next_command(&mut transport) => match command {
+                    Some(Ok(command)) => binding.handle_server_input(command, Instant::now()),
+                    Some(Err(e)) => {
+                        log::error!("Server produced invalid command: {e:?}");
+                        Ok(())
+                    }
+                    None => {
+                        log::error!("NATS Server disconnected TCP Stream");
+                        binding.handle_disconnected(Instant::now());
+                        Ok(())
+                    }
+                },
+            };
+            if let Err(e) = outcome {
+                log::error!("Giving up on the connection: {e}");
+                break authorization_error.map_or_else(|| e.into(), NatsError::Authorization);
+            }
+        };
+
+        for closer in closers {
+            let _ = closer.send(reason.clone());
+        }
+        reason
+    }
+}
+
+/// How many messages the channel to a subscriber holds. Messages wait in the binding instead,
+/// where the subscriber's [PendingLimits](nats_sans_io::PendingLimits) apply to them.
+const HANDOFF_CAPACITY: usize = 1;
+
+/// Hands subscribers the messages the binding kept for them, for as long as they have room.
+/// Only subscriptions with messages waiting are visited. Unsubscribes the subscribers that have
+/// gone away, and forgets those that were `ended` once they have received everything;
+/// returns those that are full.
+fn forward(
+    binding: &mut NatsBinding,
+    subscribers: &mut HashMap<Sid, mpsc::Sender<nats_codec::Message>>,
+    ended: &mut HashSet<Sid>,
+) -> Vec<mpsc::Sender<nats_codec::Message>> {
+    let mut full = vec![];
+    for sid in binding.pending_subscriptions() {
+        // Nobody is interested in the messages anymore
+        let Some(subscriber) = subscribers.get(&sid).cloned() else {
+            while binding.poll_message_for(&sid).is_some() {}
+            continue;
+        };
+        loop {
+            let permit = match subscriber.try_reserve() {
+                Ok(permit) => permit,
+                Err(mpsc::error::TrySendError::Full(())) => {
+                    full.push((sid.clone(), subscriber.clone()));
+                    break;
                 }
-                time = recv_ping_ticker.tick() => {
-                    let _ = binding.handle_send_pong_timeout(time.into());
+                Err(mpsc::error::TrySendError::Closed(())) => {
+                    if !ended.remove(&sid) {
+                        let unsubscribe = ConnectionCommand::Unsubscribe {
+                            sid: sid.clone(),
+                            max_msgs: None,
+                        };
+                        let _ = binding.handle_client_input(unsubscribe, Instant::now());
+                    }
+                    while binding.poll_message_for(&sid).is_some() {}
+                    subscribers.remove(&sid);
+                    break;
                 }
-                time = recv_pong_ticker.tick() => {
-                    let _ = binding.handle_keep_alive_timeout(time.into());
+            };
+            match binding.poll_message_for(&sid) {
+                Some(message) => permit.send(message),
+                None => break,
+            }
+        }
+    }
+
+    let waiting: HashSet<Sid> = binding.pending_subscriptions().into_iter().collect();
+    ended.retain(|sid| {
+        if waiting.contains(sid) {
+            return true;
+        }
+        subscribers.remove(sid);
+        false
+    });
+    full.into_iter()
+        .filter(|(sid, _)| waiting.contains(sid))
+        .map(|(_, subscriber)| subscriber)
+        .collect()
+}
+
+/// Resolves once one of the `full` subscribers has room for another message, or has gone away.
+async fn room(full: Vec<mpsc::Sender<nats_codec::Message>>) {
+    let mut room: futures::stream::FuturesUnordered<_> = full
+        .into_iter()
+        .map(|subscriber| subscriber.reserve_owned())
+        .collect();
+    if room.next().await.is_none() {
+        std::future::pending().await
+    }
+}
+
+/// Hands `command` to the binding, remembering who awaits its answer.
+/// Fails only if the binding has given up on the connection.
+fn dispatch(
+    binding: &mut NatsBinding,
+    command: UserCommand,
+    subscribers: &mut HashMap<Sid, mpsc::Sender<nats_codec::Message>>,
+    requests: &mut HashMap<RequestId, oneshot::Sender<RequestResponse>>,
+    flushes: &mut HashMap<FlushId, oneshot::Sender<Result<Duration, CommandError>>>,
+    acks: &mut HashMap<AckId, oneshot::Sender<Result<(), AckError>>>,
+    closers: &mut Vec<oneshot::Sender<NatsError>>,
+) -> Result<(), NatsProtocolError> {
+    let now = Instant::now();
+    match command {
+        UserCommand::Subscribe {
+            subject,
+            options,
+            responder,
+        } => {
+            let command = ConnectionCommand::Subscribe { subject, options };
+            let response = match binding.handle_client_input(command, now)? {
+                Ok(ClientResponse::Subscribed(response)) => response,
+                Ok(_) => {
+                    log::error!("Binding did not answer subscription");
+                    return Ok(());
                 }
-                Some(command) = receiver.recv() => {
-                    binding.handle_client_input(command, Instant::now());
+                Err(e) => {
+                    let _ = responder.send(Err(e));
+                    return Ok(());
                 }
-                command = reader.next() => {
-                    match command {
-                        Some(Ok(command)) => binding.handle_server_input(command, Instant::now()),
-                        Some(Err(e)) => log::error!("Server produced invalid command: {e:?}"),
-                        None => {
-                            log::error!("NATS Server disconnected TCP Stream");
-                            break;
-                        }
-                    };
+            };
+
+            let (sender, receiver) = mpsc::channel(HANDOFF_CAPACITY);
+            subscribers.insert(response.sid.clone(), sender);
+            let _ = responder.send(Ok((response, receiver)));
+        }
+        UserCommand::Resubscribe {
+            sid,
+            subject,
+            options,
+            responder,
+        } => {
+            let command = ConnectionCommand::Resubscribe {
+                sid: sid.clone(),
+                subject,
+                options,
+            };
+            let response = match binding.handle_client_input(command, now)? {
+                Ok(ClientResponse::Subscribed(response)) => response,
+                Ok(_) => {
+                    log::error!("Binding did not answer resubscription");
+                    return Ok(());
+                }
+                Err(e) => {
+                    let _ = responder.send(Err(e));
+                    return Ok(());
                 }
             };
 
-            if let Some(tick) = binding.poll_send_ping_timeout(Instant::now()) {
-                send_ping_ticker.reset_at(tick.into());
+            // The old subscription keeps its sender until it has been drained, see [Event::Unsubscribed]
+            if let Some(sender) = subscribers.get(&sid).cloned() {
+                subscribers.insert(response.sid.clone(), sender);
             }
-
-            if let Some(tick) = binding.poll_send_pong_timeout() {
-                recv_ping_ticker.reset_at(tick.into());
+            let _ = responder.send(Ok(response));
+        }
+        UserCommand::Publish { command, responder } => {
+            let _ = responder.send(binding.handle_client_input(command, now)?.map(|_| ()));
+        }
+        UserCommand::Request { command, responder } => {
+            match binding.handle_client_input(command, now)? {
+                Ok(ClientResponse::Requested(id)) => {
+                    requests.insert(id, responder);
+                }
+                Ok(_) => log::error!("Binding did not answer request"),
+                Err(e) => {
+                    let _ = responder.send(Err(e.into()));
+                }
             }
-
-            if let Some(tick) = binding.poll_keep_alive_timeout() {
-                recv_pong_ticker.reset_at(tick.into());
+        }
+        UserCommand::Flush { responder } => {
+            match binding.handle_client_input(ConnectionCommand::Flush, now)? {
+                Ok(ClientResponse::Flushing(id)) => {
+                    flushes.insert(id, responder);
+                }
+                Ok(_) => log::error!("Binding did not answer flush"),
+                Err(e) => {
+                    let _ = responder.send(Err(e));
+                }
+            }
+        }
+        UserCommand::Acknowledge { command, responder } => {
+            match binding.handle_client_input(command, now)? {
+                Ok(ClientResponse::Acknowledging(id)) => {
+                    acks.insert(id, responder);
+                }
+                Ok(_) => log::error!("Binding did not answer acknowledged publish"),
+                Err(e) => {
+                    let _ = responder.send(Err(e.into()));
+                }
+            }
+        }
+        UserCommand::ServerInfo { responder } => {
+            let _ = responder.send(binding.server_info().cloned());
+        }
+        UserCommand::Statistics { responder } => {
+            let _ = responder.send(binding.statistics());
+        }
+        UserCommand::Forward(command) => {
+            // Messages still waiting for a subscriber that has gone away are of no use
+            if let ConnectionCommand::Unsubscribe {
+                sid,
+                max_msgs: None,
+            } = &command
+            {
+                subscribers.remove(sid);
+            }
+            if let Err(e) = binding.handle_client_input(command, now)? {
+                log::error!("Rejected client command: {e}");
             }
         }
+        UserCommand::ShutDown { command, responder } => {
+            closers.push(responder);
+            if let Err(e) = binding.handle_client_input(command, now)? {
+                log::error!("Rejected client command: {e}");
+            }
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
+/// Transmits whatever the binding has enqueued.
+async fn transmit(
+    binding: &mut NatsBinding,
+    writer: &mut Writer,
+) -> Result<(), impl std::fmt::Debug> {
+    while let Some(transmit) = binding.poll_transmit() {
+        writer.feed(transmit).await?;
+    }
+    writer.flush().await
+}
+
+/// Transmits what the binding enqueued before closing, then closes the transport.
+async fn close(binding: &mut NatsBinding, writer: &mut Writer) {
+    while let Some(transmit) = binding.poll_transmit() {
+        if let Err(e) = writer.feed(transmit).await {
+            log::warn!("Failed to write to TCP Stream whilst closing: {e:?}");
+            return;
+        }
+    }
+    if let Err(e) = writer.close().await {
+        log::warn!("Failed to close TCP Stream: {e:?}");
+    }
+}
+
+/// Resolves once `deadline` has passed; never if there is none.
+async fn timeout(deadline: Option<Instant>) -> Instant {
+    match deadline {
+        Some(deadline) => time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+    Instant::now()
+}
+
+fn framed(tcp: TcpStream) -> (Reader, Writer) {
+    let (reader, writer) = tcp.into_split();
+    (
+        FramedRead::new(BufReader::new(reader), nats_codec::ServerCodec),
+        FramedWrite::new(BufWriter::new(writer), nats_codec::ClientCodec),
+    )
+}
+
+type Connecting = Pin<Box<dyn Future<Output = Option<(Reader, Writer)>> + Send>>;
+
+/// Gives up once `timeout` has elapsed, as the operating system may take minutes to.
+async fn reconnect(
+    server: ServerAddr,
+    attempt: usize,
+    timeout: Duration,
+) -> Option<(Reader, Writer)> {
+    log::info!("Connecting to {server}, attempt #{attempt}");
+    let connect = TcpStream::connect((server.host.as_str(), server.port));
+    match time::timeout(timeout, connect).await {
+        Ok(Ok(tcp)) => Some(framed(tcp)),
+        Ok(Err(e)) => {
+            log::warn!("Failed to connect to {server}: {e:?}");
+            None
+        }
+        Err(_) => {
+            log::warn!("Timed out connecting to {server}");
+            None
+        }
+    }
+}
+
+async fn connect(connecting: &mut Option<Connecting>) -> Option<(Reader, Writer)> {
+    match connecting {
+        Some(connecting) => connecting.await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn next_command(
+    transport: &mut Option<(Reader, Writer)>,
+) -> Option<Result<nats_codec::ServerCommand, nats_codec::ServerDecodeError>> {
+    match transport {
+        Some((reader, _)) => reader.next().await,
+        None => std::future::pending().await,
     }
 }
 
 #[derive(Debug)]
 pub struct UserHandle {
-    chan: mpsc::Sender<nats_sans_io::ConnectionCommand>,
+    chan: mpsc::Sender<UserCommand>,
 }
 
 impl UserHandle {
-    pub async fn subscribe(&self, subject: String, options: SubscriptionOptions) -> Subscriber {
-        let (sender, receiver) = oneshot::channel();
-        let _ = self
-            .chan
-            .send(nats_sans_io::ConnectionCommand::Subscribe {
+    /// Hands a command to the task running the connection, and awaits its answer.
+    async fn ask<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> UserCommand,
+    ) -> Result<T, NatsError> {
+        let (responder, receiver) = oneshot::channel();
+        self.chan
+            .send(command(responder))
+            .await
+            .map_err(|_| NatsError::Closed)?;
+
+        receiver.await.map_err(|_| NatsError::Closed)
+    }
+
+    pub async fn subscribe(
+        &self,
+        subject: String,
+        options: SubscriptionOptions,
+    ) -> Result<Subscriber, NatsError> {
+        let (SubscribeResponse { sid, .. }, msg_chan) = self
+            .ask(|responder| UserCommand::Subscribe {
                 subject,
                 options,
-                sender,
+                responder,
             })
-            .await;
-
-        let SubscribeResponse {
-            msg_chan,
-            sid,
-            max_msgs,
-        } = receiver.await.unwrap();
+            .await??;
 
-        let messages = if let Some(limit) = max_msgs {
-            ReceiverStream::new(msg_chan).take(limit.into()).boxed()
-        } else {
-            ReceiverStream::new(msg_chan).boxed()
-        };
+        // The binding ends the stream once `max_msgs` have been received
+        let messages = tokio_stream::wrappers::ReceiverStream::new(msg_chan).boxed();
 
-        Subscriber {
+        Ok(Subscriber {
             sid,
             conn_chan: self.chan.clone(),
             messages,
-        }
+        })
     }
 
-    pub async fn publish(&self, subject: String, message: tokio_util::bytes::Bytes) {
-        self.chan
-            .send(nats_sans_io::ConnectionCommand::Publish {
+    /// Fails if the server is unable to handle the message, in which case nothing is sent.
+    pub async fn publish(
+        &self,
+        subject: String,
+        message: tokio_util::bytes::Bytes,
+    ) -> Result<(), NatsError> {
+        self.publish_with_options(subject, message, PublishOptions::default())
+            .await
+    }
+
+    pub async fn publish_with_options(
+        &self,
+        subject: String,
+        message: tokio_util::bytes::Bytes,
+        options: PublishOptions,
+    ) -> Result<(), NatsError> {
+        self.ask(|responder| UserCommand::Publish {
+            command: ConnectionCommand::Publish {
+                subject,
+                payload: message,
+                options,
+            },
+            responder,
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    /// Waits until the server has acknowledged the publish with `+OK`,
+    /// which requires [ConnectOptions::verbose](nats_sans_io::ConnectOptions::verbose).
+    pub async fn publish_acknowledged(
+        &self,
+        subject: String,
+        message: tokio_util::bytes::Bytes,
+        options: PublishOptions,
+    ) -> Result<(), NatsError> {
+        self.ask(|responder| UserCommand::Acknowledge {
+            command: ConnectionCommand::Publish {
                 subject,
                 payload: message,
+                options: PublishOptions {
+                    acknowledge: true,
+                    ..options
+                },
+            },
+            responder,
+        })
+        .await??;
+
+        Ok(())
+    }
+
+    pub async fn request(
+        &self,
+        subject: String,
+        payload: tokio_util::bytes::Bytes,
+        timeout: Duration,
+    ) -> Result<nats_codec::Message, NatsError> {
+        self.request_with_headers(subject, payload, None, timeout)
+            .await
+    }
+
+    pub async fn request_with_headers(
+        &self,
+        subject: String,
+        payload: tokio_util::bytes::Bytes,
+        headers: Option<nats_codec::HeaderMap>,
+        timeout: Duration,
+    ) -> Result<nats_codec::Message, NatsError> {
+        let reply = self
+            .ask(|responder| UserCommand::Request {
+                command: ConnectionCommand::Request {
+                    subject,
+                    payload,
+                    headers,
+                    timeout,
+                },
+                responder,
             })
+            .await??;
+
+        Ok(reply)
+    }
+
+    /// Waits until the server has processed everything published so far.
+    pub async fn flush(&self, timeout: Duration) -> Result<(), NatsError> {
+        self.rtt(timeout).await.map(|_| ())
+    }
+
+    /// Measures the round-trip time to the server.
+    pub async fn rtt(&self, timeout: Duration) -> Result<Duration, NatsError> {
+        let flush = self.ask(|responder| UserCommand::Flush { responder });
+        Ok(time::timeout(timeout, flush)
             .await
-            .unwrap();
+            .map_err(|_| NatsError::TimedOut)???)
     }
 
-    pub async fn close(self) {
-        /* 
-        self.chan
-            .send(nats_sans_io::ConnectionCommand::Close)
+    /// What the server announced about itself; `None` whilst not connected.
+    pub async fn server_info(&self) -> Result<Option<nats_codec::Info>, NatsError> {
+        self.ask(|responder| UserCommand::ServerInfo { responder })
             .await
+    }
+
+    /// Snapshot of the connection's counters, see [NatsBinding::statistics].
+    pub async fn statistics(&self) -> Result<Statistics, NatsError> {
+        self.ask(|responder| UserCommand::Statistics { responder })
+            .await
+    }
+
+    /// Drains every subscription, flushes what was published and closes the connection.
+    /// Resolves once the connection has been closed, failing if it ended otherwise.
+    pub async fn drain(&self) -> Result<(), NatsError> {
+        self.shut_down(ConnectionCommand::Drain { sid: None }).await
+    }
+
+    /// Closes the connection once what was published so far has been transmitted.
+    /// Resolves once the connection has been closed, failing if it ended otherwise.
+    pub async fn close(self) -> Result<(), NatsError> {
+        self.shut_down(ConnectionCommand::Close).await
+    }
+
+    async fn shut_down(&self, command: ConnectionCommand) -> Result<(), NatsError> {
+        match self
+            .ask(|responder| UserCommand::ShutDown { command, responder })
+            .await?
+        {
+            NatsError::Closed => Ok(()),
+            reason => Err(reason),
+        }
+    }
+}
+
+/// Feeds `wire`, as sent by a server, into `binding`.
+#[cfg(test)]
+fn receive(binding: &mut NatsBinding, wire: &str) {
+    use tokio_util::codec::Decoder;
+
+    let mut buffer = tokio_util::bytes::BytesMut::from(wire);
+    while let Some(command) = nats_codec::ServerCodec.decode(&mut buffer).unwrap() {
+        binding
+            .handle_server_input(command, Instant::now())
             .unwrap();
-        */
     }
 }
+
+#[tokio::test]
+async fn forward_waits_for_room() {
+    let mut binding = NatsBinding::new(nats_sans_io::Timeouts {
+        ping_interval: Duration::from_secs(10),
+        pong_delay: Duration::from_secs(10),
+        keep_alive: Duration::from_secs(10),
+    });
+    receive(
+        &mut binding,
+        "INFO {\"server_id\":\"test\",\"server_name\":\"test\",\"version\":\"2.10.17\",\"go\":\"go1.22.4\",\"host\":\"0.0.0.0\",\"port\":4222,\"headers\":true,\"max_payload\":1048576,\"proto\":1}\r\nPONG\r\n",
+    );
+    while binding.poll_transmit().is_some() {}
+
+    let mut subscribers = HashMap::new();
+    let mut ended = HashSet::new();
+    let mut subscribe = |binding: &mut NatsBinding| {
+        let subscribe = ConnectionCommand::Subscribe {
+            subject: "handoff".into(),
+            options: SubscriptionOptions::default(),
+        };
+        let Ok(Ok(ClientResponse::Subscribed(SubscribeResponse { sid, .. }))) =
+            binding.handle_client_input(subscribe, Instant::now())
+        else {
+            panic!("Expected subscription");
+        };
+        let (sender, receiver) = mpsc::channel(HANDOFF_CAPACITY);
+        subscribers.insert(sid.clone(), sender);
+        (sid, receiver)
+    };
+    let (slow, mut slow_messages) = subscribe(&mut binding);
+    let (gone, gone_messages) = subscribe(&mut binding);
+    let (done, mut done_messages) = subscribe(&mut binding);
+    while binding.poll_transmit().is_some() {}
+    for sid in [&slow, &slow, &gone, &done] {
+        receive(&mut binding, &format!("MSG handoff {sid} 5\r\nhello\r\n"));
+    }
+    drop(gone_messages);
+    ended.insert(done.clone());
+
+    // The rest waits in the binding until the subscriber has room
+    let full = forward(&mut binding, &mut subscribers, &mut ended);
+    assert_eq!(full.len(), 1);
+    assert_eq!(binding.pending_subscriptions(), vec![slow.clone()]);
+    let room = room(full);
+    tokio::pin!(room);
+    assert!(futures::poll!(&mut room).is_pending());
+    assert!(slow_messages.recv().await.is_some());
+    assert!(futures::poll!(&mut room).is_ready());
+    assert!(forward(&mut binding, &mut subscribers, &mut ended).is_empty());
+    assert!(slow_messages.recv().await.is_some());
+    assert!(binding.pending_subscriptions().is_empty());
+
+    // Subscribers that went away are unsubscribed; those that were ended are forgotten once served
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(nats_codec::ClientCommand::Unsubscribe(
+            nats_codec::Unsubscribe {
+                sid: gone.clone(),
+                max_msgs: None,
+            }
+        ))
+    );
+    assert!(done_messages.recv().await.is_some());
+    assert_eq!(subscribers.keys().collect::<Vec<_>>(), [&slow]);
+    assert!(ended.is_empty());
+}
@@ -2,5 +2,7 @@ mod connection;
 mod subscriber;
 
 pub use connection::{NatsError, NatsOverTcp, UserHandle};
-pub use nats_sans_io::SubscriptionOptions;
+pub use nats_sans_io::{
+    AckError, CommandError, ConnectOptions, PublishOptions, Statistics, SubscriptionOptions,
+};
 pub use subscriber::Subscriber;
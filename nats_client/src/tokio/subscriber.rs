@@ -3,12 +3,14 @@ use std::task::Poll;
 use futures::{stream::BoxStream, StreamExt as _};
 use nats_codec::Message;
 use nats_sans_io::ConnectionCommand;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
+
+use super::{connection::UserCommand, NatsError, SubscriptionOptions};
 
 pub struct Subscriber {
     pub sid: String,
     pub messages: BoxStream<'static, nats_codec::Message>,
-    pub conn_chan: mpsc::Sender<nats_sans_io::ConnectionCommand>,
+    pub(crate) conn_chan: mpsc::Sender<UserCommand>,
 }
 
 impl std::fmt::Debug for Subscriber {
@@ -20,17 +22,51 @@ impl std::fmt::Debug for Subscriber {
     }
 }
 
+impl Subscriber {
+    /// Unsubscribes, but keeps the stream going until the messages in flight have been received.
+    pub async fn drain(&self) {
+        let drain = ConnectionCommand::Drain {
+            sid: Some(self.sid.clone()),
+        };
+        // Nothing is in flight if the connection is gone
+        let _ = self.conn_chan.send(UserCommand::Forward(drain)).await;
+    }
+
+    /// Replaces the subscription with one of `subject` and `options`, without missing messages in between.
+    /// The stream carries on with the messages of both until the old subscription has been drained.
+    /// Keeps the channel capacity of the original subscription.
+    pub async fn resubscribe(
+        &mut self,
+        subject: String,
+        options: SubscriptionOptions,
+    ) -> Result<(), NatsError> {
+        let (responder, receiver) = oneshot::channel();
+        self.conn_chan
+            .send(UserCommand::Resubscribe {
+                sid: self.sid.clone(),
+                subject,
+                options,
+                responder,
+            })
+            .await
+            .map_err(|_| NatsError::Closed)?;
+
+        let response = receiver.await.map_err(|_| NatsError::Closed)??;
+        self.sid = response.sid;
+        Ok(())
+    }
+}
+
 impl Drop for Subscriber {
     fn drop(&mut self) {
         let sender = self.conn_chan.clone();
         let sid = self.sid.clone();
 
-        sender
-            .try_send(ConnectionCommand::Unsubscribe {
-                sid: sid.to_string(),
-                max_msgs: None,
-            })
-            .unwrap();
+        // The connection may have been closed already
+        let _ = sender.try_send(UserCommand::Forward(ConnectionCommand::Unsubscribe {
+            sid: sid.to_string(),
+            max_msgs: None,
+        }));
     }
 }
 
@@ -23,11 +23,24 @@ pub fn parse_headers(
     }
 
     let mut spliterator = slice_spliterator(header_buffer, &crate::CRLF);
-    let Some((b"NATS/1.0", _)) = spliterator.next() else {
+    let Some((version_line, _)) = spliterator.next() else {
+        return Err(HeaderDecodeError::MissingNatsVersion);
+    };
+    let Some(status_line) = version_line.strip_prefix(b"NATS/1.0") else {
         return Err(HeaderDecodeError::MissingNatsVersion);
     };
 
     let mut headers = HashMap::new();
+    if !status_line.is_empty() {
+        let (status, description) = parse_status(status_line)?;
+        headers.insert(HeaderName(crate::STATUS_HEADER.into()), vec![status]);
+        if let Some(description) = description {
+            headers.insert(
+                HeaderName(crate::DESCRIPTION_HEADER.into()),
+                vec![description],
+            );
+        }
+    }
     loop {
         let Some((slice, offset)) = spliterator.next() else {
             return Err(HeaderDecodeError::BadLength);
@@ -47,6 +60,33 @@ pub fn parse_headers(
     Ok(crate::HeaderMap(headers))
 }
 
+/// Inline status, e.g. `NATS/1.0 503` or `NATS/1.0 408 Request Timeout`
+fn parse_status(
+    status_line: &[u8],
+) -> Result<(HeaderValue, Option<HeaderValue>), HeaderDecodeError> {
+    let Some(status_line) = status_line.strip_prefix(b" ") else {
+        return Err(HeaderDecodeError::MissingNatsVersion);
+    };
+    let Ok(status_line) = std::str::from_utf8(status_line) else {
+        return Err(HeaderDecodeError::BadHeaderValue);
+    };
+
+    let (status, description) = match status_line.split_once(' ') {
+        Some((status, description)) => (status, Some(description.trim())),
+        None => (status_line, None),
+    };
+    if status.len() != 3 || !status.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(HeaderDecodeError::BadHeaderValue);
+    }
+
+    Ok((
+        HeaderValue(status.into()),
+        description
+            .filter(|description| !description.is_empty())
+            .map(|description| HeaderValue(description.into())),
+    ))
+}
+
 fn parse_header(slice: &[u8]) -> Result<(HeaderName, HeaderValue), HeaderDecodeError> {
     let mut colon_iter = memchr_iter(b':', slice);
     let (Some(colon_index), None) = (colon_iter.next(), colon_iter.next()) else {
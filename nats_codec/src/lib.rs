@@ -10,6 +10,11 @@ use tokio_util::bytes::Bytes;
 
 pub(crate) const BUFSIZE_LIMIT: usize = u16::MAX as usize;
 
+/// Name of the header carrying the inline status of a message, e.g. `503` when there are no responders.
+pub const STATUS_HEADER: &str = "Status";
+/// Name of the header carrying the description that may follow an inline status.
+pub const DESCRIPTION_HEADER: &str = "Description";
+
 const CR: u8 = 0x0D;
 const LF: u8 = 0x0A;
 const CRLF: [u8; 2] = [CR, LF];
@@ -90,7 +95,7 @@ pub enum ServerCommand {
     Err(String),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Message {
     pub subject: String,
     pub sid: String,
@@ -123,6 +128,96 @@ impl std::convert::From<Msg> for Message {
     }
 }
 
+impl HeaderName {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for HeaderName {
+    fn from(value: &str) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<String> for HeaderName {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl HeaderValue {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<&str> for HeaderValue {
+    fn from(value: &str) -> Self {
+        Self(value.into())
+    }
+}
+
+impl From<String> for HeaderValue {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl Default for HeaderMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self(HashMap::new())
+    }
+
+    /// Adds `value` to the values already associated with `name`.
+    pub fn append(&mut self, name: impl Into<HeaderName>, value: impl Into<HeaderValue>) {
+        self.0.entry(name.into()).or_default().push(value.into());
+    }
+
+    /// First value associated with `name`.
+    pub fn get(&self, name: &str) -> Option<&HeaderValue> {
+        self.get_all(name).first()
+    }
+
+    pub fn get_all(&self, name: &str) -> &[HeaderValue] {
+        self.0
+            .get(&HeaderName(name.into()))
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HeaderName, &HeaderValue)> {
+        self.0
+            .iter()
+            .flat_map(|(name, values)| values.iter().map(move |value| (name, value)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Inline status of the message, see [STATUS_HEADER].
+    pub fn status(&self) -> Option<u16> {
+        self.get(STATUS_HEADER)?.as_str().parse().ok()
+    }
+
+    /// How many bytes the headers occupy on the wire, including the version line and trailing CRLF.
+    pub fn encoded_len(&self) -> usize {
+        let version = "NATS/1.0".len() + CRLF.len();
+        let headers: usize = self
+            .iter()
+            .map(|(name, value)| name.0.len() + ": ".len() + value.0.len() + CRLF.len())
+            .sum();
+
+        version + headers + CRLF.len()
+    }
+}
+
 pub struct ServerCodec;
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -164,6 +259,37 @@ pub struct HPublish {
     pub payload: Bytes,
 }
 
+impl Publish {
+    pub fn new(subject: String, reply_to: Option<String>, payload: Bytes) -> Self {
+        Self {
+            subject,
+            reply_to,
+            bytes: payload.len(),
+            payload,
+        }
+    }
+}
+
+impl HPublish {
+    /// Derives [Self::header_bytes] and [Self::total_bytes] from `headers` and `payload`.
+    pub fn new(
+        subject: String,
+        reply_to: Option<String>,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> Self {
+        let header_bytes = headers.encoded_len();
+        Self {
+            subject,
+            reply_to,
+            header_bytes,
+            total_bytes: header_bytes + payload.len(),
+            headers,
+            payload,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct Subscribe {
     pub subject: String,
@@ -291,6 +417,44 @@ mod hmsg {
     }
 }
 
+#[cfg(test)]
+mod status {
+    use crate::{ServerCodec, DESCRIPTION_HEADER, STATUS_HEADER};
+    use tokio_stream::StreamExt;
+    use tokio_util::codec::FramedRead;
+
+    #[tokio::test]
+    async fn no_responders() {
+        let mut reader = FramedRead::new(
+            &b"HMSG _INBOX.abc.1 9 16 16\r\nNATS/1.0 503\r\n\r\n\r\n"[..],
+            ServerCodec,
+        );
+        let Some(crate::ServerCommand::HMsg(hmsg)) = reader.try_next().await.unwrap() else {
+            panic!("Expected HMSG");
+        };
+        assert_eq!(hmsg.headers.status(), Some(503));
+        assert!(hmsg.payload.is_empty());
+        assert_eq!(reader.try_next().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn description() {
+        let mut reader = FramedRead::new(
+            &b"HMSG _INBOX.abc.1 9 42 42\r\nNATS/1.0 408 Request Timeout\r\nFoo: Bar\r\n\r\n\r\n"[..],
+            ServerCodec,
+        );
+        let Some(crate::ServerCommand::HMsg(hmsg)) = reader.try_next().await.unwrap() else {
+            panic!("Expected HMSG");
+        };
+        assert_eq!(hmsg.headers.get(STATUS_HEADER).unwrap().as_str(), "408");
+        assert_eq!(
+            hmsg.headers.get(DESCRIPTION_HEADER).unwrap().as_str(),
+            "Request Timeout"
+        );
+        assert_eq!(hmsg.headers.get("Foo").unwrap().as_str(), "Bar");
+    }
+}
+
 #[cfg(test)]
 mod pingpong {
     use crate::{ClientCodec, ServerCodec};
@@ -595,6 +759,38 @@ mod hpub {
     }
 }
 
+#[cfg(test)]
+mod encoding {
+    use crate::{ClientCodec, ClientCommand, HPublish, HeaderMap};
+    use bytes::{Bytes, BytesMut};
+    use tokio_util::codec::{Decoder as _, Encoder as _};
+
+    #[test]
+    fn hpub_roundtrip() {
+        let mut headers = HeaderMap::new();
+        headers.append("BREAKFAST", "donut");
+        headers.append("BREAKFAST", "eggs");
+
+        let hpub = HPublish::new(
+            "MORNING.MENU".into(),
+            Some("INBOX.1".into()),
+            headers,
+            Bytes::from_static(b"Yum!"),
+        );
+        assert_eq!(hpub.header_bytes, 47);
+        assert_eq!(hpub.total_bytes, 51);
+
+        let mut buffer = BytesMut::new();
+        ClientCodec
+            .encode(ClientCommand::HPublish(hpub.clone()), &mut buffer)
+            .unwrap();
+        assert_eq!(
+            ClientCodec.decode(&mut buffer).unwrap(),
+            Some(ClientCommand::HPublish(hpub))
+        );
+    }
+}
+
 #[cfg(test)]
 mod connection {
     use crate::ClientCodec;
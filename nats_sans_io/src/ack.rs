@@ -0,0 +1,79 @@
+use std::collections::VecDeque;
+
+use nats_codec::ClientCommand;
+
+use crate::CommandError;
+
+/// Identifies an acknowledged publish towards [Event::Acknowledged](crate::Event::Acknowledged).
+pub type AckId = u64;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AckError {
+    #[error("Server responded with an error: {0}")]
+    Server(String),
+
+    #[error("Publish was rejected: {0}")]
+    Rejected(#[from] CommandError),
+
+    #[error("Publish was discarded before it was sent")]
+    Discarded,
+
+    #[error("Connection was lost before the server acknowledged the publish")]
+    ConnectionLost,
+}
+
+/// Correlates the `+OK` and `-ERR` sent by a server in verbose mode with the commands they answer.
+/// The server answers every command but `PING` and `PONG`, in the order they were sent.
+#[derive(Debug, Default)]
+pub struct Acks {
+    verbose: bool,
+    /// How many commands have been transmitted on this connection.
+    transmitted: usize,
+    /// Commands awaiting acknowledgement that were not transmitted yet, by their position in the transmit order.
+    tagged: VecDeque<(usize, AckId)>,
+    /// One slot per transmitted command that was not answered yet, oldest first.
+    slots: VecDeque<Option<AckId>>,
+}
+
+impl Acks {
+    pub fn new(verbose: bool) -> Self {
+        Self {
+            verbose,
+            ..Default::default()
+        }
+    }
+
+    /// Awaits the acknowledgement of the last of the `queued` commands not transmitted yet.
+    pub fn tag(&mut self, queued: usize, id: AckId) {
+        self.tagged.push_back((self.transmitted + queued - 1, id));
+    }
+
+    /// Removes the tag of the command at `index` of those not transmitted yet, if it has one.
+    pub fn untag(&mut self, index: usize) -> Option<AckId> {
+        let position = self.transmitted + index;
+        match self.tagged.front() {
+            Some((tagged, _)) if *tagged == position => self.tagged.pop_front().map(|(_, id)| id),
+            _ => None,
+        }
+    }
+
+    /// Opens a slot for `command`, which has just been transmitted.
+    pub fn transmitted(&mut self, command: &ClientCommand) {
+        let id = self.untag(0);
+        self.transmitted += 1;
+
+        if self.verbose && !matches!(command, ClientCommand::Ping | ClientCommand::Pong) {
+            self.slots.push_back(id);
+        }
+    }
+
+    /// Resolves the oldest slot, returning its ID if anyone awaits it.
+    pub fn answered(&mut self) -> Option<AckId> {
+        self.slots.pop_front().flatten()
+    }
+
+    /// Transmitted commands whose answer is still awaited.
+    pub fn unanswered(&self) -> impl Iterator<Item = AckId> + '_ {
+        self.slots.iter().flatten().copied()
+    }
+}
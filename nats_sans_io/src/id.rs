@@ -0,0 +1,158 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::Sid;
+
+/// Chooses the identifiers a binding announces to the server,
+/// see [NatsBinding::with_id_generator](crate::NatsBinding::with_id_generator).
+pub trait IdGenerator: std::fmt::Debug + Send {
+    /// Identifies a new subscription; must not repeat for the lifetime of the binding.
+    fn next_sid(&mut self) -> Sid;
+
+    /// Unique, unguessable token, e.g. for the inbox that replies to requests are sent to.
+    fn next_nuid(&mut self) -> String;
+}
+
+const DIGITS: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+const PREFIX_LEN: usize = 12;
+const SEQUENCE_LEN: usize = 10;
+/// `62^10`, i.e. the number of distinct sequences.
+const MAX_SEQUENCE: u64 = 839_299_365_868_340_224;
+const MIN_INCREMENT: u64 = 33;
+const MAX_INCREMENT: u64 = 333;
+
+/// Sequential SIDs, and NUIDs in the format of the Go client: 22 base62 digits,
+/// a random prefix followed by a sequence that advances by a random increment.
+/// The prefix is drawn afresh once the sequence is exhausted.
+#[derive(Debug)]
+pub struct NuidGenerator {
+    sid: u64,
+    prefix: [u8; PREFIX_LEN],
+    sequence: u64,
+    increment: u64,
+    rng: StdRng,
+}
+
+impl Default for NuidGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NuidGenerator {
+    /// Seeded from the operating system, so that the NUIDs cannot be predicted.
+    pub fn new() -> Self {
+        Self::from_rng(StdRng::from_entropy())
+    }
+
+    fn from_rng(rng: StdRng) -> Self {
+        let mut generator = Self {
+            sid: 0,
+            prefix: [0; PREFIX_LEN],
+            sequence: 0,
+            increment: 0,
+            rng,
+        };
+        generator.randomize();
+        generator
+    }
+
+    fn randomize(&mut self) {
+        for digit in &mut self.prefix {
+            *digit = DIGITS[self.rng.gen_range(0..DIGITS.len())];
+        }
+        self.sequence = self.rng.gen_range(0..MAX_SEQUENCE);
+        self.increment = self.rng.gen_range(MIN_INCREMENT..MAX_INCREMENT);
+    }
+}
+
+impl IdGenerator for NuidGenerator {
+    fn next_sid(&mut self) -> Sid {
+        self.sid += 1;
+        self.sid.to_string()
+    }
+
+    fn next_nuid(&mut self) -> String {
+        self.sequence += self.increment;
+        if self.sequence >= MAX_SEQUENCE {
+            self.randomize();
+        }
+
+        let mut nuid = [0; PREFIX_LEN + SEQUENCE_LEN];
+        nuid[..PREFIX_LEN].copy_from_slice(&self.prefix);
+        let mut sequence = self.sequence;
+        for digit in nuid[PREFIX_LEN..].iter_mut().rev() {
+            *digit = DIGITS[(sequence % DIGITS.len() as u64) as usize];
+            sequence /= DIGITS.len() as u64;
+        }
+        nuid.iter().copied().map(char::from).collect()
+    }
+}
+
+/// Like [NuidGenerator], but every generator of the same `seed` produces the same identifiers,
+/// so that tests and simulations are reproducible. Not suitable for production.
+#[derive(Debug)]
+pub struct SeededIdGenerator(NuidGenerator);
+
+impl SeededIdGenerator {
+    pub fn new(seed: u64) -> Self {
+        Self(NuidGenerator::from_rng(StdRng::seed_from_u64(seed)))
+    }
+}
+
+impl IdGenerator for SeededIdGenerator {
+    fn next_sid(&mut self) -> Sid {
+        self.0.next_sid()
+    }
+
+    fn next_nuid(&mut self) -> String {
+        self.0.next_nuid()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{IdGenerator, NuidGenerator, SeededIdGenerator, DIGITS, PREFIX_LEN};
+
+    #[test]
+    fn nuid_format() {
+        let mut generator = NuidGenerator::new();
+        let nuids: Vec<String> = (0..1000).map(|_| generator.next_nuid()).collect();
+
+        for nuid in &nuids {
+            assert_eq!(nuid.len(), 22);
+            assert!(nuid.bytes().all(|digit| DIGITS.contains(&digit)));
+            // The prefix only changes once the sequence is exhausted
+            assert_eq!(nuid[..PREFIX_LEN], nuids[0][..PREFIX_LEN]);
+        }
+        assert_eq!(nuids.iter().collect::<HashSet<_>>().len(), nuids.len());
+        assert_ne!(NuidGenerator::new().next_nuid(), nuids[0]);
+    }
+
+    #[test]
+    fn sequence_rolls_over() {
+        let mut generator = NuidGenerator::new();
+        let first = generator.next_nuid();
+        generator.sequence = super::MAX_SEQUENCE - 1;
+
+        let rolled = generator.next_nuid();
+        assert_ne!(rolled[..PREFIX_LEN], first[..PREFIX_LEN]);
+        assert_eq!(rolled.len(), 22);
+    }
+
+    #[test]
+    fn seeded_is_reproducible() {
+        let mut first = SeededIdGenerator::new(42);
+        let mut second = SeededIdGenerator::new(42);
+
+        for _ in 0..10 {
+            assert_eq!(first.next_nuid(), second.next_nuid());
+            assert_eq!(first.next_sid(), second.next_sid());
+        }
+        assert_ne!(
+            SeededIdGenerator::new(7).next_nuid(),
+            SeededIdGenerator::new(42).next_nuid()
+        );
+    }
+}
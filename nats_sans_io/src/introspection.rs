@@ -0,0 +1,44 @@
+use std::time::Instant;
+
+use crate::{RequestId, Sid};
+
+/// Stage of the connection's lifecycle, see [NatsBinding::lifecycle](crate::NatsBinding::lifecycle).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lifecycle {
+    /// Waiting for the server to send `INFO`.
+    AwaitingInfo,
+    /// `CONNECT` was sent; waiting for the server to answer the `PING` that follows it.
+    AwaitingPong,
+    /// The connection is established.
+    Connected,
+    /// The connection is established, but being drained before it is closed.
+    Draining,
+    /// The connection was lost; `attempt` counts the attempts made so far to establish a new one.
+    Reconnecting { attempt: usize },
+    /// The first message the server sent was not `INFO`.
+    ProtocolError,
+    /// The connection was lost, and no further attempts to reconnect will be made.
+    ConnectionLost,
+    /// The connection was closed on purpose.
+    Closed,
+}
+
+/// A subscription that has not ended yet, see [NatsBinding::subscriptions](crate::NatsBinding::subscriptions).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubscriptionInfo {
+    pub sid: Sid,
+    pub subject: String,
+    pub queue_group: Option<String>,
+    /// How many messages the server has sent for the subscription.
+    pub delivered: usize,
+    /// How many more messages the subscriber expects; `None` if it is unlimited.
+    pub remaining: Option<usize>,
+}
+
+/// A request awaiting its reply, see [NatsBinding::outstanding_requests](crate::NatsBinding::outstanding_requests).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OutstandingRequest {
+    pub id: RequestId,
+    /// When the request fails with [RequestError::TimedOut](crate::RequestError::TimedOut) unless answered.
+    pub deadline: Instant,
+}
@@ -1,10 +1,32 @@
+mod ack;
+mod id;
+mod introspection;
+mod multiplex;
+mod publish;
+mod request;
+mod server_pool;
+#[cfg(test)]
+mod simulation;
 mod state;
+mod statistics;
 mod subscription;
 
+pub use ack::{AckError, AckId};
+pub use id::{IdGenerator, NuidGenerator, SeededIdGenerator};
+pub use introspection::{Lifecycle, OutstandingRequest, SubscriptionInfo};
+pub use publish::{CommandError, PublishOptions};
+pub use request::{RequestError, RequestId, RequestResponse};
+pub use server_pool::{Server, ServerAddr, ServerAddrError, ServerPool, DEFAULT_PORT};
 pub use state::ConnState;
-pub use subscription::{SubscribeResponse, SubscriptionOptions};
+pub use statistics::{Statistics, SubscriptionStatistics};
+pub use subscription::{
+    PendingLimits, Sid, SlowConsumerPolicy, SubscribeResponse, SubscriptionOptions,
+};
 
-use state::{AwaitingInfo, InfoReceived, Step};
+use state::{
+    is_authorization_error, AwaitingInfo, AwaitingPong, Closed, ConnectionDrain, InfoReceived,
+    Preliminary, Reconnecting, Session, Step,
+};
 
 use std::{
     num::NonZeroUsize,
@@ -12,13 +34,17 @@ use std::{
 };
 
 use bytes::Bytes;
-use nats_codec::{ClientCommand, ServerCommand};
-use tokio::sync::{mpsc, oneshot};
+use nats_codec::{ClientCommand, HeaderMap, ServerCommand};
+use rand::Rng;
 
 #[derive(Debug)]
 struct State {
     conn_state: ConnState,
+    session: Session,
     timeouts: Timeouts,
+    /// `None` if the binding should not attempt to reconnect.
+    reconnect: Option<ReconnectOptions>,
+    handshake: HandshakeOptions,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -33,33 +59,220 @@ pub struct Timeouts {
     pub keep_alive: Duration,
 }
 
+#[derive(Clone, Copy, Debug)]
+pub struct ReconnectOptions {
+    /// How many consecutive attempts to each server may fail before it is no longer tried.
+    /// Once no server is left, the connection is considered lost. `None` retries forever.
+    pub max_attempts: Option<NonZeroUsize>,
+    /// How long to wait before the first attempt; doubles with every failed attempt.
+    pub backoff: Duration,
+    /// Upper bound for the exponential growth of [Self::backoff].
+    pub max_backoff: Duration,
+    /// Adds a random delay of up to this length to every attempt,
+    /// so that clients disconnected at the same time do not reconnect in lockstep.
+    pub jitter: Duration,
+    /// How many bytes of payload may be buffered from publishes issued whilst disconnected.
+    pub buffer_size: usize,
+}
+
+impl Default for ReconnectOptions {
+    /// Mirrors the defaults of the Go client.
+    fn default() -> Self {
+        Self {
+            max_attempts: NonZeroUsize::new(60),
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(2),
+            jitter: Duration::from_millis(100),
+            buffer_size: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ReconnectOptions {
+    /// How long to wait before attempt number `attempt + 1`.
+    fn delay(&self, attempt: usize, rng: &mut impl Rng) -> Duration {
+        let exponent = attempt.min(16) as u32;
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+
+        if self.jitter.is_zero() {
+            backoff
+        } else {
+            backoff + rng.gen_range(Duration::ZERO..=self.jitter)
+        }
+    }
+}
+
+/// Bounds establishing a connection, i.e. the time until the server sent `INFO`,
+/// and what is buffered in the meantime.
+#[derive(Clone, Copy, Debug)]
+pub struct HandshakeOptions {
+    /// How long the server may take to send `INFO` once the transport is established.
+    pub timeout: Duration,
+    /// How many commands may be buffered until connected; further ones fail with [CommandError::BacklogFull].
+    pub max_backlog: usize,
+    /// How many bytes of payload may be buffered until connected; further ones fail with [CommandError::BacklogFull].
+    /// When reconnecting, [ReconnectOptions::buffer_size] applies as well.
+    pub max_backlog_bytes: usize,
+}
+
+impl Default for HandshakeOptions {
+    /// Mirrors the connect timeout and reconnect buffer of the Go client.
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(2),
+            max_backlog: 64 * 1024,
+            max_backlog_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+/// Sent to the server with `CONNECT`.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectOptions {
+    /// Have the server answer every command with `+OK`, so that publishes may await it,
+    /// see [PublishOptions::acknowledge].
+    pub verbose: bool,
+    /// Keep the server from delivering the binding's own publishes to its subscriptions.
+    /// Requires a server that speaks protocol version 1, otherwise the connection is given up on.
+    pub no_echo: bool,
+    /// Have the server answer requests nobody is subscribed to with a 503 status,
+    /// so that they fail with [RequestError::NoResponders] instead of timing out.
+    pub no_responders: bool,
+}
+
 #[derive(Debug)]
 pub enum ConnectionCommand {
+    /// Answered with [ClientResponse::Subscribed]; messages are returned by [NatsBinding::poll_message].
     Subscribe {
         subject: String,
         options: SubscriptionOptions,
-
-        // `oneshot::Sender::send` is a synchronous function, which makes this ok :)
-        sender: oneshot::Sender<SubscribeResponse>,
     },
     Unsubscribe {
-        sid: String,
+        sid: Sid,
         max_msgs: Option<NonZeroUsize>,
     },
+    /// Replaces the subscription `sid` with one of `subject` and `options`, answered with
+    /// [ClientResponse::Subscribed]. The new `SUB` is sent before `sid` is drained, so no message
+    /// is missed; until [Event::Unsubscribed] for `sid`, messages may arrive for both.
+    Resubscribe {
+        sid: Sid,
+        subject: String,
+        options: SubscriptionOptions,
+    },
+    /// Answered with [ClientResponse::Acknowledging] if [PublishOptions::acknowledge] is set.
     Publish {
         subject: String,
         payload: Bytes,
+        options: PublishOptions,
+    },
+    /// Answered with [ClientResponse::Flushing]; completion is signalled by [Event::Flushed].
+    Flush,
+    /// Unsubscribes `sid`, but keeps delivering the messages that are in flight until the server
+    /// confirms that there are no more, see [Event::Unsubscribed].
+    /// `None` drains every subscription, then flushes the publishes and closes the connection, see [Event::Closed].
+    Drain { sid: Option<Sid> },
+    /// Closes the connection once what was enqueued so far has been transmitted, see [Event::Closed].
+    Close,
+    /// Answered with [ClientResponse::Requested]; the reply is returned by [NatsBinding::poll_reply].
+    Request {
+        subject: String,
+        payload: Bytes,
+        headers: Option<HeaderMap>,
+        /// How long to wait for the reply.
+        timeout: Duration,
     },
 }
 
-#[derive(Debug)]
-pub struct NatsBinding {
-    state: State,
+/// How [NatsBinding::handle_client_input] answered a [ConnectionCommand].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ClientResponse {
+    Accepted,
+    Subscribed(SubscribeResponse),
+    Requested(RequestId),
+    Flushing(FlushId),
+    Acknowledging(AckId),
+}
+
+/// Identifies a [ConnectionCommand::Flush] towards [Event::Flushed].
+pub type FlushId = u64;
+
+/// Notifications for the driver of a [NatsBinding], retrieved with [NatsBinding::poll_event].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    /// The server answered the `PING` that follows `CONNECT`, i.e. it accepted the connection.
+    /// Commands issued before are sent from now on.
+    Connected,
+    /// The server answered `CONNECT` with `-ERR`. Unless a connection was established before,
+    /// the binding gives up with [Event::ConnectionLost]; otherwise it reconnects.
+    ConnectRejected { error: String },
+    /// The connection is considered lost; the transport should be closed.
+    Disconnected,
+    /// A new transport to `server` should be established. Its outcome must be reported with
+    /// [NatsBinding::handle_connected] or [NatsBinding::handle_connect_failed].
+    Reconnect { attempt: usize, server: ServerAddr },
+    /// No further attempts to reconnect will be made.
+    ConnectionLost,
+    /// The connection was closed on purpose; the transport should be closed
+    /// once [NatsBinding::poll_transmit] has nothing left.
+    Closed,
+    /// The server does not speak the protocol; the connection is given up on.
+    ProtocolError { error: String },
+    /// The server is about to shut down. If reconnecting is enabled and the pool has another server,
+    /// the binding moves on to it after a random delay of up to [ReconnectOptions::jitter].
+    LameDuck,
+    /// The server rejected the credentials, and is about to close the connection.
+    AuthorizationFailed { error: String },
+    /// The subscriber of `sid` has exceeded its [PendingLimits]; `dropped` messages were discarded so far.
+    SlowConsumer { sid: Sid, dropped: usize },
+    /// The binding ended the subscription `sid` on its own; messages that were delivered before
+    /// are still returned by [NatsBinding::poll_message].
+    Unsubscribed { sid: Sid },
+    /// The server has processed everything sent before the flush `id`, which took `rtt` to confirm.
+    Flushed { id: FlushId, rtt: Duration },
+    /// The server answered the publish `id` with `+OK`, or failed it with `-ERR`.
+    Acknowledged {
+        id: AckId,
+        result: Result<(), AckError>,
+    },
+    /// A publish to `subject` that was accepted whilst not connected will not be sent after all,
+    /// e.g. because it exceeds the `max_payload` of the server that was connected to eventually,
+    /// the reconnect buffer is full, or the connection was closed before it was established.
+    /// Publishes awaiting acknowledgement are failed with [Event::Acknowledged] instead,
+    /// and requests with [RequestError::Rejected], see [NatsBinding::poll_reply].
+    PublishDiscarded {
+        subject: String,
+        error: CommandError,
+    },
+}
+
+/// Why a [NatsBinding] cannot carry on, returned by its `handle_*` methods.
+/// The transport should be closed, and the binding dropped.
+#[derive(thiserror::Error, Clone, Debug, PartialEq, Eq)]
+pub enum NatsProtocolError {
+    #[error("Server did not start with INFO")]
+    NotInfoReceived,
+
+    #[error("Server rejected the connection: {0}")]
+    ConnectRejected(String),
+
+    /// Like [Self::ConnectRejected], but the server rejected the credentials.
+    #[error("Server rejected the credentials: {0}")]
+    AuthorizationFailed(String),
+
+    #[error("Connection was lost, and no further attempts to reconnect will be made")]
+    ConnectionLost,
+
+    /// See [ConnectOptions::no_echo].
+    #[error("Server does not support disabling echo")]
+    EchoNotSupported,
 }
 
 #[derive(Debug)]
-pub struct ConnectionHandle {
-    pub chan: mpsc::Sender<ConnectionCommand>,
+pub struct NatsBinding {
+    state: State,
 }
 
 impl NatsBinding {
@@ -67,92 +280,585 @@ impl NatsBinding {
         let state = State {
             conn_state: ConnState::AwaitingInfo(AwaitingInfo {
                 preliminary: vec![],
+                buffered_bytes: 0,
+                deadline: None,
             }),
+            session: Session::default(),
             timeouts,
+            reconnect: None,
+            handshake: HandshakeOptions::default(),
         };
 
         Self { state }
     }
 
-    pub fn handle_server_input(&mut self, command: ServerCommand, now: Instant) {
-        if let Some(change) = self.state.conn_state.step(command, now) {
-            self.state.conn_state = change;
-        }
+    /// Reconnect instead of giving up once the connection is lost.
+    pub fn with_reconnect(mut self, options: ReconnectOptions) -> Self {
+        self.state.reconnect = Some(options);
+        self
     }
 
-    pub fn handle_client_input(&mut self, command: ConnectionCommand, now: Instant) {
-        if let Some(change) = self.state.conn_state.step(command, now) {
-            self.state.conn_state = change;
-        }
+    /// Bounds establishing each connection, see [HandshakeOptions].
+    pub fn with_handshake_options(mut self, options: HandshakeOptions) -> Self {
+        self.state.handshake = options;
+        self
     }
 
-    pub fn poll_transmit(&mut self) -> Option<ClientCommand> {
-        let ConnState::InfoReceived(InfoReceived {
-            buffered_transmits, ..
-        }) = &mut self.state.conn_state
-        else {
-            return None;
+    /// Drivers bound establishing the transport by [HandshakeOptions::timeout] as well.
+    pub fn handshake_options(&self) -> &HandshakeOptions {
+        &self.state.handshake
+    }
+
+    /// Configures what is announced to the server with `CONNECT`.
+    pub fn with_connect_options(mut self, options: ConnectOptions) -> Self {
+        self.state.session.connect = options;
+        self
+    }
+
+    /// Chooses SIDs and inbox names with `ids` instead of a [NuidGenerator],
+    /// e.g. a [SeededIdGenerator] to make them reproducible.
+    pub fn with_id_generator(mut self, ids: impl IdGenerator + 'static) -> Self {
+        self.state.session.ids = Box::new(ids);
+        self
+    }
+
+    /// Subscriptions share the subscription on the server of an earlier one whose subject matches everything
+    /// theirs does, e.g. `orders.eu` that of `orders.>`; each receives the messages its own subject matches.
+    /// The server subscription is unsubscribed once the last one sharing it is gone.
+    /// Suits many local subscribers of overlapping subjects. Queue groups are never shared,
+    /// and `max_msgs` is counted by the binding instead of the server.
+    pub fn with_multiplexing(mut self) -> Self {
+        self.state.session.multiplexer = Some(Default::default());
+        self
+    }
+
+    /// Connect to one of `servers` instead of a transport the driver established up front.
+    /// The binding requests the transport to each server with [Event::Reconnect].
+    pub fn with_servers(mut self, servers: impl IntoIterator<Item = ServerAddr>) -> Self {
+        let State {
+            conn_state,
+            session,
+            ..
+        } = &mut self.state;
+        session.servers.extend(servers, false, &mut session.rng);
+
+        let (preliminary, buffered_bytes) = match conn_state {
+            ConnState::AwaitingInfo(AwaitingInfo {
+                preliminary,
+                buffered_bytes,
+                ..
+            }) => (std::mem::take(preliminary), *buffered_bytes),
+            _ => (vec![], 0),
         };
-        let command = buffered_transmits.pop_front();
-        log::trace!("Polled {command:?}");
-        command
+        // Like before any connection was made, buffer whatever is issued until connected
+        *conn_state = ConnState::Reconnecting(Reconnecting {
+            reconnect_at: None,
+            preliminary,
+            buffered_bytes,
+            buffer_size: usize::MAX,
+        });
+
+        self.request_transport();
+        self
     }
 
-    /// What happens when [Self::poll_send_ping_timeout]'s timestamp is exceeded
-    pub fn handle_send_ping_timeout(&mut self, now: Instant) {
+    /// Adds `server` to the pool, e.g. the server that the driver established the initial transport to.
+    pub fn add_server(&mut self, server: ServerAddr) {
+        let session = &mut self.state.session;
+        session.servers.extend([server], false, &mut session.rng);
+    }
+
+    /// Fails once the binding has given up on the connection, see [NatsProtocolError].
+    pub fn handle_server_input(
+        &mut self,
+        command: ServerCommand,
+        now: Instant,
+    ) -> Result<(), NatsProtocolError> {
+        self.health()?;
         let State {
-            timeouts,
             conn_state,
+            session,
+            reconnect,
             ..
         } = &mut self.state;
 
-        let ConnState::InfoReceived(InfoReceived {
-            buffered_transmits,
-            keep_alive,
+        if let (ConnState::AwaitingInfo(_), ServerCommand::Info(info)) = (&*conn_state, &command) {
+            if session.connect.no_echo && info.proto < 1 {
+                log::error!("Connection lost! The NATS server cannot be told to disable echo");
+                *conn_state = ConnState::ConnectionLost;
+                session.events.push_back(Event::Disconnected);
+                session.events.push_back(Event::ConnectionLost);
+                return Err(NatsProtocolError::EchoNotSupported);
+            }
+        }
+        if let Some(change) = conn_state.step(session, command, now) {
+            *conn_state = change;
+        }
+        if let ConnState::AwaitingPong(AwaitingPong {
+            rejected: Some(error),
             ..
         }) = conn_state
-        else {
-            return;
+        {
+            let error = std::mem::take(error);
+            self.reject_connection(now);
+            return match self.health() {
+                Err(NatsProtocolError::ConnectionLost) if is_authorization_error(&error) => {
+                    Err(NatsProtocolError::AuthorizationFailed(error))
+                }
+                Err(NatsProtocolError::ConnectionLost) => {
+                    Err(NatsProtocolError::ConnectRejected(error))
+                }
+                health => health,
+            };
+        }
+
+        // Clients leaving a lame duck at the same time would all arrive at the next server at once
+        if let (ConnState::InfoReceived(inner), Some(options)) = (conn_state, reconnect) {
+            if inner.lame_duck && inner.migrate_at.is_none() && session.servers.servers().len() > 1
+            {
+                let jitter = if options.jitter.is_zero() {
+                    Duration::ZERO
+                } else {
+                    session.rng.gen_range(Duration::ZERO..=options.jitter)
+                };
+                inner.migrate_at = Some(now + jitter);
+            }
+        }
+        self.health()
+    }
+
+    /// Rejects `command` with a [CommandError] if the server is unable to handle it,
+    /// in which case nothing is sent. Fails once the binding has given up on the connection.
+    pub fn handle_client_input(
+        &mut self,
+        command: ConnectionCommand,
+        now: Instant,
+    ) -> Result<Result<ClientResponse, CommandError>, NatsProtocolError> {
+        self.health()?;
+        Ok(self.handle_command(command, now))
+    }
+
+    fn handle_command(
+        &mut self,
+        command: ConnectionCommand,
+        now: Instant,
+    ) -> Result<ClientResponse, CommandError> {
+        let State {
+            conn_state,
+            session,
+            handshake,
+            ..
+        } = &mut self.state;
+
+        match conn_state {
+            ConnState::InfoReceived(inner) => inner.validate(&command)?,
+            ConnState::Closed(_) => return Err(CommandError::Closed),
+            _ => conn_state.admit(&command, handshake)?,
+        }
+        let acknowledge = matches!(
+            &command,
+            ConnectionCommand::Publish { options, .. } if options.acknowledge
+        );
+        if acknowledge && !session.connect.verbose {
+            return Err(CommandError::NotVerbose);
+        }
+        match (session.draining, &command) {
+            (
+                Some(_),
+                ConnectionCommand::Subscribe { .. }
+                | ConnectionCommand::Resubscribe { .. }
+                | ConnectionCommand::Request { .. },
+            )
+            | (Some(ConnectionDrain::Publishes), ConnectionCommand::Publish { .. }) => {
+                return Err(CommandError::Draining);
+            }
+            _ => {}
+        }
+        if let ConnectionCommand::Resubscribe { sid, .. } = &command {
+            if session
+                .subscriptions
+                .get(sid)
+                .is_none_or(|s| s.draining || s.replaced)
+            {
+                return Err(CommandError::UnknownSubscription(sid.clone()));
+            }
+        }
+
+        let response = match command {
+            ConnectionCommand::Subscribe { subject, options } => {
+                ClientResponse::Subscribed(conn_state.subscribe(session, subject, options))
+            }
+            ConnectionCommand::Resubscribe {
+                sid,
+                subject,
+                options,
+            } => ClientResponse::Subscribed(
+                conn_state.resubscribe(session, sid, subject, options, now),
+            ),
+            ConnectionCommand::Flush => ClientResponse::Flushing(conn_state.flush(session, now)),
+            command if acknowledge => ClientResponse::Acknowledging(
+                conn_state.publish_acknowledged(session, command, now),
+            ),
+            ConnectionCommand::Request {
+                subject,
+                payload,
+                headers,
+                timeout,
+            } => ClientResponse::Requested(
+                conn_state.request(session, subject, payload, headers, timeout, now),
+            ),
+            command => {
+                if let Some(change) = conn_state.step(session, command, now) {
+                    *conn_state = change;
+                }
+                ClientResponse::Accepted
+            }
         };
 
-        let command = match keep_alive.last_ping_sent_at {
-            Some(received_at) if now.duration_since(received_at) >= timeouts.ping_interval => {
-                Some(ClientCommand::Ping)
+        Ok(response)
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<ClientCommand> {
+        let State {
+            conn_state,
+            session,
+            ..
+        } = &mut self.state;
+
+        let command = match conn_state {
+            ConnState::InfoReceived(InfoReceived {
+                buffered_transmits,
+                acks,
+                ..
+            }) => {
+                let command = buffered_transmits.pop_front()?;
+                acks.transmitted(&command);
+                command
             }
-            None => Some(ClientCommand::Ping),
-            _ => None,
+            ConnState::AwaitingPong(AwaitingPong {
+                buffered_transmits, ..
+            })
+            | ConnState::Closed(Closed { buffered_transmits }) => buffered_transmits.pop_front()?,
+            _ => return None,
         };
+        log::trace!("Polled {command:?}");
+        session.statistics.transmitted(&command);
+        Some(command)
+    }
 
-        if let Some(command) = command {
-            buffered_transmits.push_back(command);
-            keep_alive.last_ping_sent_at = Some(now);
-            log::trace!("Enqueued `PING`");
+    pub fn poll_event(&mut self) -> Option<Event> {
+        self.state.session.events.pop_front()
+    }
+
+    /// Round-trip time to the server, as measured by the most recent `PING` of the current connection.
+    pub fn rtt(&self) -> Option<Duration> {
+        match &self.state.conn_state {
+            ConnState::InfoReceived(InfoReceived { keep_alive, .. }) => keep_alive.rtt,
+            _ => None,
         }
     }
 
-    /// Returns the timestamp when we next expect [Self::handle_send_ping_timeout] to be called.
-    /// i.e. When should the next `PING` be sent.
-    pub fn poll_send_ping_timeout(&self, now: Instant) -> Option<Instant> {
+    /// What the server announced about itself, including updates made after connecting.
+    pub fn server_info(&self) -> Option<&nats_codec::Info> {
+        match &self.state.conn_state {
+            ConnState::InfoReceived(InfoReceived { info, .. }) => Some(info),
+            _ => None,
+        }
+    }
+
+    /// Snapshot of the counters maintained since the binding was created.
+    pub fn statistics(&self) -> Statistics {
+        let session = &self.state.session;
+        let subscriptions = session
+            .subscriptions
+            .iter()
+            .map(|(sid, subscription)| {
+                let statistics = SubscriptionStatistics {
+                    subject: subscription.subject.clone(),
+                    delivered: subscription.delivered,
+                    dropped: subscription.dropped,
+                    pending_messages: subscription.pending_messages,
+                    pending_bytes: subscription.pending_bytes,
+                };
+                (sid.clone(), statistics)
+            })
+            .collect();
+
+        Statistics {
+            subscriptions,
+            ..session.statistics.clone()
+        }
+    }
+
+    /// Which stage of its lifecycle the connection is in.
+    pub fn lifecycle(&self) -> Lifecycle {
+        let session = &self.state.session;
+        match &self.state.conn_state {
+            ConnState::AwaitingInfo(_) => Lifecycle::AwaitingInfo,
+            ConnState::AwaitingPong(_) => Lifecycle::AwaitingPong,
+            ConnState::InfoReceived(_) if session.draining.is_some() => Lifecycle::Draining,
+            ConnState::InfoReceived(_) => Lifecycle::Connected,
+            ConnState::Reconnecting(_) => Lifecycle::Reconnecting {
+                attempt: session.reconnect_attempts,
+            },
+            ConnState::NotInfoReceived => Lifecycle::ProtocolError,
+            ConnState::ConnectionLost => Lifecycle::ConnectionLost,
+            ConnState::Closed(_) => Lifecycle::Closed,
+        }
+    }
+
+    /// Subscriptions that have not ended yet, in no particular order.
+    /// Those made whilst not connected are included, even though the server does not know about them yet.
+    pub fn subscriptions(&self) -> Vec<SubscriptionInfo> {
+        self.state
+            .session
+            .subscriptions
+            .iter()
+            .map(|(sid, subscription)| SubscriptionInfo {
+                sid: sid.clone(),
+                subject: subscription.subject.clone(),
+                queue_group: subscription.queue_group.clone(),
+                delivered: subscription.delivered,
+                remaining: subscription.remaining(),
+            })
+            .collect()
+    }
+
+    /// How many commands are waiting for [Self::poll_transmit]. Commands issued whilst not connected
+    /// are held back until the handshake is complete, so they are not counted before.
+    pub fn pending_transmits(&self) -> usize {
+        match &self.state.conn_state {
+            ConnState::AwaitingPong(AwaitingPong {
+                buffered_transmits, ..
+            })
+            | ConnState::InfoReceived(InfoReceived {
+                buffered_transmits, ..
+            })
+            | ConnState::Closed(Closed { buffered_transmits }) => buffered_transmits.len(),
+            _ => 0,
+        }
+    }
+
+    /// Requests awaiting their reply, the earliest deadline first.
+    pub fn outstanding_requests(&self) -> Vec<OutstandingRequest> {
+        let mut requests: Vec<OutstandingRequest> = self
+            .state
+            .session
+            .requests
+            .pending
+            .iter()
+            .map(|(&id, &deadline)| OutstandingRequest { id, deadline })
+            .collect();
+        requests.sort_by_key(|request| (request.deadline, request.id));
+        requests
+    }
+
+    /// Messages received for subscriptions, in the order they arrived.
+    pub fn poll_message(&mut self) -> Option<(Sid, nats_codec::Message)> {
+        self.state.session.poll_message()
+    }
+
+    /// Like [Self::poll_message], but only for the subscription `sid`, so that the messages of
+    /// a subscriber that has no room for them stay pending, subject to its [PendingLimits].
+    pub fn poll_message_for(&mut self, sid: &str) -> Option<nats_codec::Message> {
+        self.state.session.poll_message_for(sid)
+    }
+
+    /// Subscriptions that have messages waiting for [Self::poll_message_for], in no particular order.
+    pub fn pending_subscriptions(&self) -> Vec<Sid> {
+        self.state.session.messages.keys().cloned().collect()
+    }
+
+    /// Outcomes of requests, in the order they were determined.
+    pub fn poll_reply(&mut self) -> Option<(RequestId, RequestResponse)> {
+        self.state.session.requests.replies.pop_front()
+    }
+
+    /// The transport was closed, or failed, underneath the binding.
+    pub fn handle_disconnected(&mut self, now: Instant) {
+        match &self.state.conn_state {
+            ConnState::ConnectionLost | ConnState::Closed(_) => {}
+            // An attempt is in progress, so the new transport never came to be
+            ConnState::Reconnecting(Reconnecting {
+                reconnect_at: None, ..
+            }) => self.reconnect_failed(now),
+            // Already waiting for the next attempt
+            ConnState::Reconnecting(_) => {}
+            _ => self.lose_connection(now),
+        }
+    }
+
+    /// The transport requested by [Event::Reconnect], or the one the driver established up front,
+    /// has been established. The server has [HandshakeOptions::timeout] to send `INFO`
+    /// and to accept `CONNECT`.
+    pub fn handle_connected(&mut self, now: Instant) {
         let State {
-            timeouts,
             conn_state,
+            handshake,
             ..
-        } = &self.state;
+        } = &mut self.state;
+        let deadline = Some(now + handshake.timeout);
+
+        match conn_state {
+            ConnState::Reconnecting(Reconnecting {
+                preliminary,
+                buffered_bytes,
+                ..
+            }) => {
+                *conn_state = ConnState::AwaitingInfo(AwaitingInfo {
+                    preliminary: std::mem::take(preliminary),
+                    buffered_bytes: *buffered_bytes,
+                    deadline,
+                });
+            }
+            ConnState::AwaitingInfo(awaiting) if awaiting.deadline.is_none() => {
+                awaiting.deadline = deadline;
+            }
+            _ => log::warn!("Connected despite not reconnecting"),
+        }
+    }
 
-        let ConnState::InfoReceived(InfoReceived { keep_alive, .. }) = conn_state else {
+    /// The transport requested by [Event::Reconnect] could not be established.
+    pub fn handle_connect_failed(&mut self, now: Instant) {
+        let ConnState::Reconnecting(Reconnecting {
+            reconnect_at: None, ..
+        }) = &self.state.conn_state
+        else {
+            log::warn!("Failed to connect despite not reconnecting");
+            return;
+        };
+
+        self.reconnect_failed(now);
+    }
+
+    /// Processes every timeout that has expired by `now`, see [Self::poll_timeout].
+    pub fn handle_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        // Checked first, as there is no point in keeping alive a connection that was lost
+        self.handle_keep_alive_timeout(now)?;
+        self.handle_handshake_timeout(now)?;
+        self.handle_send_ping_timeout(now)?;
+        self.handle_send_pong_timeout(now)?;
+        self.handle_request_timeout(now)?;
+        self.handle_migrate_timeout(now)?;
+        self.handle_reconnect_timeout(now)
+    }
+
+    /// Returns the timestamp when we next expect [Self::handle_timeout] to be called,
+    /// i.e. the earliest of the timeouts below.
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        [
+            self.poll_send_ping_timeout(),
+            self.poll_send_pong_timeout(),
+            self.poll_keep_alive_timeout(),
+            self.poll_handshake_timeout(),
+            self.poll_request_timeout(),
+            self.poll_migrate_timeout(),
+            self.poll_reconnect_timeout(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// What happens when [Self::poll_reconnect_timeout]'s timestamp is exceeded.
+    fn handle_reconnect_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        match self.poll_reconnect_timeout() {
+            Some(deadline) if now >= deadline => self.request_transport(),
+            _ => {}
+        }
+        self.health()
+    }
+
+    /// Returns the timestamp when we next expect [Self::handle_reconnect_timeout] to be called.
+    /// i.e. When should the next attempt to reconnect be made.
+    fn poll_reconnect_timeout(&self) -> Option<Instant> {
+        let ConnState::Reconnecting(Reconnecting { reconnect_at, .. }) = &self.state.conn_state
+        else {
             return None;
         };
 
-        match keep_alive.last_ping_sent_at {
-            // Ping has been previously sent
-            Some(sent_at) => Some(sent_at + timeouts.ping_interval),
-            // Ping has never been sent; now is the time!
-            None => Some(now),
+        *reconnect_at
+    }
+
+    /// What happens when [Self::poll_migrate_timeout]'s timestamp is exceeded.
+    fn handle_migrate_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        match self.poll_migrate_timeout() {
+            Some(deadline) if now >= deadline => {
+                log::info!("Moving on from lame duck server");
+                self.lose_connection(now);
+                self.request_transport();
+            }
+            _ => {}
+        }
+        self.health()
+    }
+
+    /// Returns the timestamp when we next expect [Self::handle_migrate_timeout] to be called.
+    /// i.e. When should the connection to a lame duck server be replaced.
+    fn poll_migrate_timeout(&self) -> Option<Instant> {
+        let ConnState::InfoReceived(InfoReceived { migrate_at, .. }) = &self.state.conn_state
+        else {
+            return None;
+        };
+
+        *migrate_at
+    }
+
+    /// What happens when [Self::poll_handshake_timeout]'s timestamp is exceeded.
+    fn handle_handshake_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        match self.poll_handshake_timeout() {
+            Some(deadline) if now >= deadline => {
+                log::error!(
+                    "Connection lost! The NATS server did not complete the handshake within {}s",
+                    self.state.handshake.timeout.as_secs_f64()
+                );
+                self.lose_connection(now);
+            }
+            _ => {}
+        }
+        self.health()
+    }
+
+    /// Returns the timestamp when we next expect [Self::handle_handshake_timeout] to be called.
+    /// i.e. When should the server have sent `INFO`, and answered the `PING` following `CONNECT`, by.
+    fn poll_handshake_timeout(&self) -> Option<Instant> {
+        match &self.state.conn_state {
+            ConnState::AwaitingInfo(AwaitingInfo { deadline, .. })
+            | ConnState::AwaitingPong(AwaitingPong { deadline, .. }) => *deadline,
+            _ => None,
+        }
+    }
+
+    /// What happens when [Self::poll_request_timeout]'s timestamp is exceeded.
+    fn handle_request_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        self.state.session.requests.expire(now);
+        self.health()
+    }
+
+    /// Returns the timestamp when we next expect [Self::handle_request_timeout] to be called.
+    /// i.e. When does the earliest pending request time out.
+    fn poll_request_timeout(&self) -> Option<Instant> {
+        self.state.session.requests.next_deadline()
+    }
+
+    /// What happens when [Self::poll_send_ping_timeout]'s timestamp is exceeded
+    fn handle_send_ping_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
+        match self.poll_send_ping_timeout() {
+            Some(deadline) if now >= deadline => {}
+            _ => return self.health(),
         }
+        let ConnState::InfoReceived(inner) = &mut self.state.conn_state else {
+            return self.health();
+        };
+
+        inner.ping(None, now);
+        inner.keep_alive.last_ping_sent_at = Some(now);
+        log::trace!("Enqueued `PING`");
+        Ok(())
     }
 
-    /// What happens when [Self::poll_recv_ping_timeout]'s timestamp is exceeded.
-    pub fn handle_send_pong_timeout(&mut self, now: Instant) {
+    /// What happens when [Self::poll_send_pong_timeout]'s timestamp is exceeded.
+    fn handle_send_pong_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
         let State {
             timeouts,
             conn_state,
@@ -165,7 +871,7 @@ impl NatsBinding {
             ..
         }) = conn_state
         else {
-            return;
+            return self.health();
         };
 
         let command = match keep_alive.last_ping_received_at {
@@ -180,11 +886,12 @@ impl NatsBinding {
             keep_alive.last_ping_received_at = None;
             log::trace!("Enqueued `PONG`");
         }
+        Ok(())
     }
 
-    /// Returns the timestamp when we next expect [Self::handle_recv_ping_timeout] to be called.
+    /// Returns the timestamp when we next expect [Self::handle_send_pong_timeout] to be called.
     /// i.e. When should the next `PONG` be sent.
-    pub fn poll_send_pong_timeout(&self) -> Option<Instant> {
+    fn poll_send_pong_timeout(&self) -> Option<Instant> {
         let State {
             timeouts,
             conn_state,
@@ -200,7 +907,7 @@ impl NatsBinding {
     }
 
     /// What happens when [Self::poll_keep_alive_timeout]'s timestamp is exceeded.
-    pub fn handle_keep_alive_timeout(&mut self, now: Instant) {
+    fn handle_keep_alive_timeout(&mut self, now: Instant) -> Result<(), NatsProtocolError> {
         let State {
             conn_state,
             timeouts,
@@ -208,7 +915,7 @@ impl NatsBinding {
         } = &mut self.state;
 
         let ConnState::InfoReceived(InfoReceived { keep_alive, .. }) = conn_state else {
-            return;
+            return self.health();
         };
 
         match keep_alive.last_pong_received_at {
@@ -218,15 +925,16 @@ impl NatsBinding {
                     "Connection lost! It has been over {}s since the NATS server sent a PONG",
                     timeouts.keep_alive.as_secs_f64()
                 );
-                *conn_state = ConnState::ConnectionLost
+                self.lose_connection(now);
             }
             _ => {}
         };
+        self.health()
     }
 
     /// Returns the timestamp when we next expect [Self::handle_keep_alive_timeout] to be called.
     /// i.e. When should the server have sent its next PONG by.
-    pub fn poll_keep_alive_timeout(&self) -> Option<Instant> {
+    fn poll_keep_alive_timeout(&self) -> Option<Instant> {
         let State {
             timeouts,
             conn_state,
@@ -241,65 +949,326 @@ impl NatsBinding {
             .last_pong_received_at
             .map(|i| i + timeouts.keep_alive)
     }
-}
-
-#[cfg(test)]
-fn info() -> Box<nats_codec::Info> {
-    serde_json::from_str(r#"{"server_id":"NC5WKM2NEXZZYVBSLD24PDKRCMRXZXSMBIYC3VLG7YS5RSD7ERST3OS4","server_name":"us-south-nats-demo","version":"2.10.17","proto":1,"git_commit":"b91de03","go":"go1.22.4","host":"0.0.0.0","port":4222,"headers":true,"tls_available":true,"max_payload":1048576,"jetstream":true,"client_id":710058,"client_ip":"176.199.209.34","nonce":"WnZZsP2OjHY8YwU","xkey":"XAHQDFJMDUWCMLSZC6U5REONIGLFHANVWQLZRSFLVBMC5RSUSGHSF5EC"}"#).unwrap()
-}
 
-#[test]
-fn timeouts() {
-    // How often do we send PING
-    let ping_interval = Duration::from_secs(2);
-    // How long do we allow to wait for sending PONG in response to PING
-    let pong_delay = Duration::from_secs(0);
-    // How long do we wait for receiving a PONG
-    let keep_alive = Duration::from_secs(3);
+    /// Returns the timestamp when we next expect [Self::handle_send_ping_timeout] to be called.
+    /// i.e. When should the next `PING` be sent; the first one is due once the connection is established.
+    fn poll_send_ping_timeout(&self) -> Option<Instant> {
+        let ConnState::InfoReceived(InfoReceived { keep_alive, .. }) = &self.state.conn_state
+        else {
+            return None;
+        };
 
-    // Tick 0 - setup
-    let now = Instant::now();
+        match keep_alive.last_ping_sent_at {
+            Some(sent_at) => Some(sent_at + self.state.timeouts.ping_interval),
+            None => keep_alive.established_at,
+        }
+    }
 
-    let mut binding = NatsBinding::new(Timeouts {
-        ping_interval: ping_interval.clone(),
-        pong_delay: pong_delay.clone(),
-        keep_alive: keep_alive.clone(),
-    });
-    assert!(matches!(
-        binding.state.conn_state,
-        ConnState::AwaitingInfo(_)
-    ));
+    /// Fails once the binding has given up on the connection.
+    fn health(&self) -> Result<(), NatsProtocolError> {
+        match &self.state.conn_state {
+            ConnState::NotInfoReceived => Err(NatsProtocolError::NotInfoReceived),
+            ConnState::ConnectionLost => Err(NatsProtocolError::ConnectionLost),
+            _ => Ok(()),
+        }
+    }
 
-    {
-        let tick = now + Duration::from_secs(0);
-        step(
-            &mut binding,
-            tick,
-            StepExpectations {
-                poll_send_ping: None,
-                poll_send_pong: None,
-                poll_keep_alive: None,
-                //enqueued: vec![ClientCommand::Connect(_)],
-            },
-        );
+    /// The server refused `CONNECT`. Unless a connection was established before, there is no reason
+    /// to believe that another attempt would fare better, so the binding gives up on the spot.
+    fn reject_connection(&mut self, now: Instant) {
+        let State {
+            conn_state,
+            session,
+            ..
+        } = &mut self.state;
+        if session.connected_before {
+            return self.lose_connection(now);
+        }
 
-        assert_eq!(binding.poll_transmit(), None);
+        log::error!("Connection lost! The NATS server rejected the initial connection");
+        *conn_state = ConnState::ConnectionLost;
+        session.events.push_back(Event::Disconnected);
+        session.events.push_back(Event::ConnectionLost);
     }
 
-    // Tick 1 - Receive INFO, PING tick is also triggered
-    {
-        let tick = now + Duration::from_secs(1);
-        binding.handle_server_input(ServerCommand::Info(info()), tick);
-
-        step(
-            &mut binding,
+    /// Tears down the current connection, retaining what can be replayed on the next one.
+    fn lose_connection(&mut self, now: Instant) {
+        let State {
+            conn_state,
+            session,
+            reconnect,
+            ..
+        } = &mut self.state;
+        session.events.push_back(Event::Disconnected);
+
+        let Some(options) = reconnect else {
+            log::error!("Connection lost! Reconnecting is disabled");
+            *conn_state = ConnState::ConnectionLost;
+            session.events.push_back(Event::ConnectionLost);
+            return;
+        };
+
+        let preliminary = match std::mem::replace(conn_state, ConnState::ConnectionLost) {
+            // The server never confirmed that the connection works
+            ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+            | ConnState::AwaitingPong(AwaitingPong { preliminary, .. }) => {
+                if let Some(server) = session.servers.current_mut() {
+                    server.failures += 1;
+                }
+                preliminary
+            }
+            // Publishes that were not transmitted yet are worth salvaging;
+            // subscriptions are replayed regardless. Unanswered flushes are repeated afterwards,
+            // confirming that the next server has processed what is replayed.
+            // Acknowledgements that are still awaited cannot be given by another server.
+            ConnState::InfoReceived(InfoReceived {
+                buffered_transmits,
+                keep_alive,
+                mut acks,
+                ..
+            }) => {
+                session
+                    .events
+                    .extend(acks.unanswered().map(|id| Event::Acknowledged {
+                        id,
+                        result: Err(AckError::ConnectionLost),
+                    }));
+
+                buffered_transmits
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(index, command)| {
+                        let ack = acks.untag(index);
+                        let (subject, payload, options) = match command {
+                            ClientCommand::Publish(nats_codec::Publish {
+                                subject,
+                                reply_to,
+                                payload,
+                                ..
+                            }) => (
+                                subject,
+                                payload,
+                                PublishOptions {
+                                    reply_to,
+                                    headers: None,
+                                    acknowledge: ack.is_some(),
+                                },
+                            ),
+                            ClientCommand::HPublish(nats_codec::HPublish {
+                                subject,
+                                reply_to,
+                                headers,
+                                payload,
+                                ..
+                            }) => (
+                                subject,
+                                payload,
+                                PublishOptions {
+                                    reply_to,
+                                    headers: Some(headers),
+                                    acknowledge: ack.is_some(),
+                                },
+                            ),
+                            _ => return None,
+                        };
+
+                        let command = ConnectionCommand::Publish {
+                            subject,
+                            payload,
+                            options,
+                        };
+                        let backlog = match ack {
+                            Some(id) => Preliminary::Acknowledged(id, command),
+                            None => Preliminary::Command(command),
+                        };
+                        Some(backlog)
+                    })
+                    .chain(
+                        keep_alive
+                            .outstanding_pings
+                            .into_iter()
+                            .filter_map(|(_, flush)| Some(Preliminary::Flush(flush?))),
+                    )
+                    .collect()
+            }
+            ConnState::Reconnecting(Reconnecting { preliminary, .. }) => preliminary,
+            ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_) => vec![],
+        };
+
+        // Discard the newest publishes that do not fit into the buffer
+        let mut buffered_bytes = 0;
+        let mut kept = Vec::with_capacity(preliminary.len());
+        for backlog in preliminary {
+            let size = match &backlog {
+                Preliminary::Command(ConnectionCommand::Publish { payload, .. })
+                | Preliminary::Acknowledged(_, ConnectionCommand::Publish { payload, .. }) => {
+                    payload.len()
+                }
+                _ => 0,
+            };
+            if buffered_bytes + size > options.buffer_size {
+                log::warn!("Reconnect buffer is full");
+                session.discard_backlog(backlog, CommandError::BacklogFull);
+                continue;
+            }
+            buffered_bytes += size;
+            kept.push(backlog);
+        }
+        let preliminary = kept;
+
+        *conn_state = ConnState::Reconnecting(Reconnecting {
+            reconnect_at: None,
+            preliminary,
+            buffered_bytes,
+            buffer_size: options.buffer_size,
+        });
+        self.schedule_reconnect(now);
+    }
+
+    /// The transport to the current server could not be established, or broke down immediately.
+    fn reconnect_failed(&mut self, now: Instant) {
+        if let Some(server) = self.state.session.servers.current_mut() {
+            server.failures += 1;
+            log::warn!(
+                "Failed to connect to {} ({} consecutive failures)",
+                server.addr,
+                server.failures
+            );
+        }
+        self.schedule_reconnect(now);
+    }
+
+    /// Waits before the next attempt, or gives up if no server is worth trying anymore.
+    fn schedule_reconnect(&mut self, now: Instant) {
+        let State {
+            conn_state,
+            session,
+            reconnect,
+            ..
+        } = &mut self.state;
+
+        let ConnState::Reconnecting(Reconnecting { reconnect_at, .. }) = conn_state else {
+            return;
+        };
+
+        if !session.servers.has_candidate(max_failures(*reconnect)) {
+            give_up(conn_state, session);
+            return;
+        }
+
+        let delay = reconnect.map_or(Duration::ZERO, |options| {
+            options.delay(session.reconnect_attempts, &mut session.rng)
+        });
+        log::info!("Reconnecting in {}s", delay.as_secs_f64());
+        *reconnect_at = Some(now + delay);
+    }
+
+    /// Asks the driver to establish a transport to the next server of the pool.
+    fn request_transport(&mut self) {
+        let State {
+            conn_state,
+            session,
+            reconnect,
+            ..
+        } = &mut self.state;
+
+        let ConnState::Reconnecting(Reconnecting { reconnect_at, .. }) = conn_state else {
+            return;
+        };
+
+        let Some(server) = session.servers.next(max_failures(*reconnect)) else {
+            give_up(conn_state, session);
+            return;
+        };
+
+        *reconnect_at = None;
+        session.reconnect_attempts += 1;
+        session.events.push_back(Event::Reconnect {
+            attempt: session.reconnect_attempts,
+            server,
+        });
+    }
+}
+
+/// How often each server of the pool may fail before it is no longer tried.
+fn max_failures(reconnect: Option<ReconnectOptions>) -> usize {
+    match reconnect {
+        Some(options) => options.max_attempts.map_or(usize::MAX, NonZeroUsize::get),
+        // Every server is still tried once when establishing the initial connection
+        None => 1,
+    }
+}
+
+fn give_up(conn_state: &mut ConnState, session: &mut Session) {
+    log::error!(
+        "Connection lost! Gave up after {} attempts to reconnect",
+        session.reconnect_attempts
+    );
+    *conn_state = ConnState::ConnectionLost;
+    session.events.push_back(Event::ConnectionLost);
+}
+
+#[cfg(test)]
+fn info() -> Box<nats_codec::Info> {
+    serde_json::from_str(r#"{"server_id":"NC5WKM2NEXZZYVBSLD24PDKRCMRXZXSMBIYC3VLG7YS5RSD7ERST3OS4","server_name":"us-south-nats-demo","version":"2.10.17","proto":1,"git_commit":"b91de03","go":"go1.22.4","host":"0.0.0.0","port":4222,"headers":true,"tls_available":true,"max_payload":1048576,"jetstream":true,"client_id":710058,"client_ip":"176.199.209.34","nonce":"WnZZsP2OjHY8YwU","xkey":"XAHQDFJMDUWCMLSZC6U5REONIGLFHANVWQLZRSFLVBMC5RSUSGHSF5EC"}"#).unwrap()
+}
+
+#[test]
+fn timeouts() {
+    // How often do we send PING
+    let ping_interval = Duration::from_secs(2);
+    // How long do we allow to wait for sending PONG in response to PING
+    let pong_delay = Duration::from_secs(0);
+    // How long do we wait for receiving a PONG
+    let keep_alive = Duration::from_secs(3);
+
+    // Tick 0 - setup
+    let now = Instant::now();
+
+    let mut binding = NatsBinding::new(Timeouts {
+        ping_interval,
+        pong_delay,
+        keep_alive,
+    });
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::AwaitingInfo(_)
+    ));
+
+    {
+        let tick = now + Duration::from_secs(0);
+        step(
+            &mut binding,
             tick,
             StepExpectations {
-                poll_send_ping: Some(tick),
+                poll_send_ping: None,
                 poll_send_pong: None,
                 poll_keep_alive: None,
+                //enqueued: vec![ClientCommand::Connect(_)],
             },
-        );
+        )
+        .unwrap();
+
+        assert_eq!(binding.poll_transmit(), None);
+    }
+
+    // Tick 1 - Receive INFO, the handshake's PING is sent along with CONNECT
+    {
+        let tick = now + Duration::from_secs(1);
+        binding
+            .handle_server_input(ServerCommand::Info(info()), tick)
+            .unwrap();
+
+        step(
+            &mut binding,
+            tick,
+            StepExpectations {
+                poll_send_ping: None,
+                poll_send_pong: None,
+                poll_keep_alive: None,
+            },
+        )
+        .unwrap();
 
         assert!(matches!(
             binding.poll_transmit(),
@@ -309,10 +1278,12 @@ fn timeouts() {
         assert_eq!(binding.poll_transmit(), None);
     }
 
-    // Tick 2 - Receive PONG
+    // Tick 2 - Receive PONG, establishing the connection
     {
         let tick = now + Duration::from_secs(2);
-        binding.handle_server_input(ServerCommand::Pong, tick);
+        binding
+            .handle_server_input(ServerCommand::Pong, tick)
+            .unwrap();
 
         step(
             &mut binding,
@@ -324,7 +1295,8 @@ fn timeouts() {
                 // Expect next PONG within given interval
                 poll_keep_alive: Some(tick + keep_alive),
             },
-        );
+        )
+        .unwrap();
 
         assert_eq!(binding.poll_transmit(), None);
     }
@@ -333,7 +1305,9 @@ fn timeouts() {
     // Also, receive PING from server at this time to later send PONG
     {
         let tick = now + Duration::from_secs(3);
-        binding.handle_server_input(ServerCommand::Ping, tick);
+        binding
+            .handle_server_input(ServerCommand::Ping, tick)
+            .unwrap();
 
         step(
             &mut binding,
@@ -343,7 +1317,8 @@ fn timeouts() {
                 poll_send_pong: Some(tick), // Receiving PING immediately starts send PONG
                 poll_keep_alive: Some(tick + keep_alive - Duration::from_secs(1)),
             },
-        );
+        )
+        .unwrap();
 
         assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
         assert_eq!(binding.poll_transmit(), Some(ClientCommand::Pong));
@@ -354,7 +1329,7 @@ fn timeouts() {
     {
         let tick = now + Duration::from_secs(7);
 
-        step(
+        let outcome = step(
             &mut binding,
             tick,
             StepExpectations {
@@ -364,6 +1339,7 @@ fn timeouts() {
             },
         );
 
+        assert_eq!(outcome, Err(NatsProtocolError::ConnectionLost));
         assert_eq!(binding.poll_transmit(), None);
         assert!(matches!(
             binding.state.conn_state,
@@ -373,50 +1349,180 @@ fn timeouts() {
 }
 
 #[test]
-fn retain_preemptive_messages() {
-    let tick = Instant::now();
-
-    let mut binding = NatsBinding::new(Timeouts {
+fn single_timeout() {
+    let now = Instant::now();
+    let timeouts = Timeouts {
         ping_interval: Duration::from_secs(10),
-        pong_delay: Duration::from_secs(10),
-        keep_alive: Duration::from_secs(10),
-    });
+        pong_delay: Duration::from_secs(2),
+        keep_alive: Duration::from_secs(15),
+    };
+
+    let mut binding = NatsBinding::new(timeouts);
+    assert_eq!(binding.poll_timeout(), None);
+
+    establish(&mut binding, info(), now);
+    // The handshake's `PING` counts as the first one
+    assert_eq!(binding.poll_timeout(), Some(now + timeouts.ping_interval));
+
+    // The earliest deadline wins, regardless of which timer it belongs to
+    let request_timeout = Duration::from_secs(5);
+    binding
+        .handle_client_input(
+            ConnectionCommand::Request {
+                subject: "service".into(),
+                payload: Bytes::new(),
+                headers: None,
+                timeout: request_timeout,
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    while binding.poll_transmit().is_some() {}
+    assert_eq!(binding.poll_timeout(), Some(now + request_timeout));
+
+    let ping_at = now + Duration::from_secs(1);
+    binding
+        .handle_server_input(ServerCommand::Ping, ping_at)
+        .unwrap();
+    assert_eq!(binding.poll_timeout(), Some(ping_at + timeouts.pong_delay));
+
+    // Handles everything that expired at once
+    let later = now + Duration::from_secs(6);
+    binding.handle_timeout(later).unwrap();
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Pong));
+    assert_eq!(binding.poll_transmit(), None);
     assert!(matches!(
-        binding.state.conn_state,
-        ConnState::AwaitingInfo(_)
+        binding.poll_reply(),
+        Some((_, Err(RequestError::TimedOut)))
     ));
+    assert_eq!(binding.poll_timeout(), Some(now + timeouts.ping_interval));
+}
 
-    binding.handle_client_input(
-        ConnectionCommand::Publish {
-            subject: "preemptive".into(),
-            payload: Bytes::from_static(b"Hello World!"),
-        },
-        tick,
+#[test]
+fn flush() {
+    let now = Instant::now();
+    let rtt = Duration::from_millis(5);
+    let backoff = Duration::from_secs(1);
+
+    let mut binding = new_binding().with_reconnect(ReconnectOptions {
+        backoff,
+        jitter: Duration::ZERO,
+        ..Default::default()
+    });
+    binding.add_server("localhost".parse().unwrap());
+    assert_eq!(binding.rtt(), None);
+    establish(&mut binding, info(), now);
+    // Measured by the handshake, which was answered instantly
+    assert_eq!(binding.rtt(), Some(Duration::ZERO));
+
+    let flush = |binding: &mut NatsBinding, now| {
+        let response = binding.handle_client_input(ConnectionCommand::Flush, now);
+        let Ok(Ok(ClientResponse::Flushing(id))) = response else {
+            panic!("Expected flush");
+        };
+        id
+    };
+
+    let flushed = flush(&mut binding, now);
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    binding
+        .handle_server_input(ServerCommand::Pong, now + rtt)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Flushed { id: flushed, rtt })
     );
+    assert_eq!(binding.rtt(), Some(rtt));
 
-    let (sender, mut receiver) = oneshot::channel();
-    binding.handle_client_input(
-        ConnectionCommand::Subscribe {
-            subject: "preemptive".into(),
-            options: SubscriptionOptions {
-                max_msgs: NonZeroUsize::new(5),
-                queue_group: None,
-            },
-            sender,
-        },
-        tick,
+    // Unanswered flushes are repeated on the next connection
+    let interrupted = flush(&mut binding, now + rtt);
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    binding.handle_disconnected(now + rtt);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+
+    let later = now + rtt + backoff;
+    binding.handle_timeout(later).unwrap();
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::Reconnect { .. })
+    ));
+    binding.handle_connected(later);
+    establish(&mut binding, info(), later);
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+
+    binding
+        .handle_server_input(ServerCommand::Pong, later + rtt * 2)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Flushed {
+            id: interrupted,
+            rtt: rtt * 2
+        })
     );
+}
+
+#[test]
+fn retain_preemptive_messages() {
+    let tick = Instant::now();
 
-    binding.handle_server_input(ServerCommand::Info(info()), tick);
+    let mut binding = new_binding();
     assert!(matches!(
         binding.state.conn_state,
-        ConnState::InfoReceived(_)
+        ConnState::AwaitingInfo(_)
     ));
 
+    binding
+        .handle_client_input(
+            ConnectionCommand::Publish {
+                subject: "preemptive".into(),
+                payload: Bytes::from_static(b"Hello World!"),
+                options: PublishOptions::default(),
+            },
+            tick,
+        )
+        .unwrap()
+        .unwrap();
+
+    let response = binding
+        .handle_client_input(
+            ConnectionCommand::Subscribe {
+                subject: "preemptive".into(),
+                options: SubscriptionOptions {
+                    max_msgs: NonZeroUsize::new(5),
+                    queue_group: None,
+                    ..Default::default()
+                },
+            },
+            tick,
+        )
+        .unwrap();
+    // Answered before the server is even aware of the subscription
+    assert!(matches!(response, Ok(ClientResponse::Subscribed(_))));
+
+    binding
+        .handle_server_input(ServerCommand::Info(info()), tick)
+        .unwrap();
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::AwaitingPong(_)
+    ));
     assert!(matches!(
         binding.poll_transmit(),
         Some(ClientCommand::Connect(_))
     ));
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    // Held back until the server has accepted `CONNECT`
+    assert_eq!(binding.poll_transmit(), None);
+
+    binding
+        .handle_server_input(ServerCommand::Pong, tick)
+        .unwrap();
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::InfoReceived(_)
+    ));
     assert_eq!(
         binding.poll_transmit(),
         Some(ClientCommand::Publish(nats_codec::Publish {
@@ -434,9 +1540,1953 @@ fn retain_preemptive_messages() {
         binding.poll_transmit(),
         Some(ClientCommand::Unsubscribe(_))
     ));
-    assert!(matches!(binding.poll_transmit(), None));
+    assert!(binding.poll_transmit().is_none());
+}
+
+#[test]
+fn reconnect_replays_subscriptions() {
+    let now = Instant::now();
+    let backoff = Duration::from_secs(1);
+
+    let mut binding = new_binding().with_reconnect(ReconnectOptions {
+        max_attempts: NonZeroUsize::new(2),
+        backoff,
+        max_backoff: backoff * 4,
+        jitter: Duration::ZERO,
+        buffer_size: "Hello World!".len(),
+    });
+    let server: ServerAddr = "localhost".parse().unwrap();
+    binding.add_server(server.clone());
+    establish(&mut binding, info(), now);
+
+    let sid = subscribe(
+        &mut binding,
+        "replayed",
+        SubscriptionOptions {
+            max_msgs: NonZeroUsize::new(5),
+            queue_group: Some("group".into()),
+            ..Default::default()
+        },
+        now,
+    );
+    while binding.poll_transmit().is_some() {}
+
+    for _ in 0..2 {
+        binding
+            .handle_server_input(
+                ServerCommand::Msg(nats_codec::Msg {
+                    subject: "replayed".into(),
+                    sid: sid.clone(),
+                    reply_to: None,
+                    bytes: 0,
+                    payload: Bytes::new(),
+                }),
+                now,
+            )
+            .unwrap();
+    }
+
+    // First attempt fails, second one succeeds
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(binding.poll_reconnect_timeout(), Some(now + backoff));
+
+    binding.handle_timeout(now + backoff).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect {
+            attempt: 1,
+            server: server.clone()
+        })
+    );
+    binding.handle_connect_failed(now + backoff);
+    assert_eq!(binding.poll_reconnect_timeout(), Some(now + backoff * 3));
+
+    // Only the first publish fits into the buffer
+    for (payload, expected) in [
+        ("Hello World!", Ok(Ok(ClientResponse::Accepted))),
+        ("Goodbye", Ok(Err(CommandError::BacklogFull))),
+    ] {
+        let response = binding.handle_client_input(
+            ConnectionCommand::Publish {
+                subject: "buffered".into(),
+                payload: Bytes::from_static(payload.as_bytes()),
+                options: PublishOptions::default(),
+            },
+            now + backoff * 2,
+        );
+        assert_eq!(response, expected);
+    }
+
+    binding.handle_timeout(now + backoff * 3).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect { attempt: 2, server })
+    );
+    binding.handle_connected(now + backoff * 3);
+    establish(&mut binding, info(), now + backoff * 3);
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Subscribe(nats_codec::Subscribe {
+            subject: "replayed".into(),
+            queue_group: Some("group".into()),
+            sid: sid.clone(),
+        }))
+    );
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+            sid,
+            max_msgs: NonZeroUsize::new(3),
+        }))
+    );
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(nats_codec::Publish {
+            subject: "buffered".into(),
+            reply_to: None,
+            bytes: "Hello World!".len(),
+            payload: Bytes::from_static(b"Hello World!")
+        }))
+    );
+    assert_eq!(binding.poll_transmit(), None);
+    assert_eq!(binding.poll_event(), None);
+}
+
+#[test]
+fn reconnect_gives_up() {
+    let now = Instant::now();
+
+    let mut binding = new_binding().with_reconnect(ReconnectOptions {
+        max_attempts: NonZeroUsize::new(1),
+        jitter: Duration::ZERO,
+        ..Default::default()
+    });
+    let server: ServerAddr = "localhost".parse().unwrap();
+    binding.add_server(server.clone());
+
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+
+    let deadline = binding.poll_reconnect_timeout().unwrap();
+    binding.handle_timeout(deadline).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect { attempt: 1, server })
+    );
+
+    binding.handle_connect_failed(deadline);
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+    assert_eq!(binding.poll_reconnect_timeout(), None);
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::ConnectionLost
+    ));
+}
+
+#[test]
+fn reconnect_to_discovered_servers() {
+    let now = Instant::now();
+    let seed: ServerAddr = "seed:4222".parse().unwrap();
+    let discovered: ServerAddr = "discovered:4222".parse().unwrap();
+
+    let mut binding = new_binding()
+        .with_reconnect(ReconnectOptions {
+            max_attempts: NonZeroUsize::new(1),
+            backoff: Duration::ZERO,
+            jitter: Duration::ZERO,
+            ..Default::default()
+        })
+        .with_servers([seed.clone()]);
+
+    // The initial connection is requested up front
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect {
+            attempt: 1,
+            server: seed.clone()
+        })
+    );
+    binding.handle_connected(now);
+
+    let mut info = info();
+    info.connect_urls = Some(vec![discovered.to_string()]);
+    establish(&mut binding, info, now);
+    assert_eq!(binding.state.session.servers.servers().len(), 2);
+
+    // The next server of the pool is tried first
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    binding.handle_timeout(now).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect {
+            attempt: 1,
+            server: discovered
+        })
+    );
+
+    binding.handle_connect_failed(now);
+    binding.handle_timeout(now).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Reconnect {
+            attempt: 2,
+            server: seed
+        })
+    );
+
+    // Each server has failed as often as permitted
+    binding.handle_connect_failed(now);
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+}
+
+#[test]
+fn lame_duck() {
+    let now = Instant::now();
+    let jitter = Duration::from_secs(1);
+    let lame: ServerAddr = "lame:4222".parse().unwrap();
+    let healthy: ServerAddr = "healthy:4222".parse().unwrap();
+
+    let mut binding = new_binding()
+        .with_reconnect(ReconnectOptions {
+            jitter,
+            ..Default::default()
+        })
+        .with_servers([lame.clone()]);
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::Reconnect { server, .. }) if server == lame
+    ));
+    binding.handle_connected(now);
+
+    let mut announced = info();
+    announced.connect_urls = Some(vec![healthy.to_string()]);
+    establish(&mut binding, announced.clone(), now);
+    let subscribe = |binding: &mut NatsBinding, subject: &str| {
+        binding
+            .handle_client_input(
+                ConnectionCommand::Subscribe {
+                    subject: subject.into(),
+                    options: SubscriptionOptions::default(),
+                },
+                now,
+            )
+            .unwrap()
+            .unwrap();
+    };
+    subscribe(&mut binding, "before");
+    subscribe(&mut binding, "replaced");
+    while binding.poll_transmit().is_some() {}
+    let replaced = binding
+        .subscriptions()
+        .into_iter()
+        .find(|subscription| subscription.subject == "replaced")
+        .unwrap()
+        .sid;
+
+    announced.ldm = Some(true);
+    binding
+        .handle_server_input(ServerCommand::Info(announced.clone()), now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::LameDuck));
+    let migrate_at = binding.poll_migrate_timeout().unwrap();
+    assert!(migrate_at >= now && migrate_at <= now + jitter);
+
+    // Announced again, but noticed only once
+    binding
+        .handle_server_input(ServerCommand::Info(announced), now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), None);
+    assert_eq!(binding.poll_migrate_timeout(), Some(migrate_at));
+
+    // New subscriptions are left to the next server, including the inbox of requests
+    subscribe(&mut binding, "after");
+    assert_eq!(binding.poll_transmit(), None);
+    binding
+        .handle_client_input(
+            ConnectionCommand::Request {
+                subject: "service".into(),
+                payload: Bytes::new(),
+                headers: None,
+                timeout: Duration::from_secs(10),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+    assert_eq!(binding.poll_transmit(), None);
+
+    // A replaced subscription keeps receiving messages from the lame duck until it is left
+    binding
+        .handle_client_input(
+            ConnectionCommand::Resubscribe {
+                sid: replaced.clone(),
+                subject: "replacement".into(),
+                options: SubscriptionOptions::default(),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(binding.poll_transmit(), None);
+
+    binding.handle_timeout(migrate_at).unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::Reconnect { server, .. }) if server == healthy
+    ));
+    binding.handle_connected(migrate_at);
+    establish(&mut binding, info(), migrate_at);
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed { sid: replaced })
+    );
+    let mut subjects = vec![];
+    while let Some(ClientCommand::Subscribe(subscribe)) = binding.poll_transmit() {
+        subjects.push(subscribe.subject);
+    }
+    assert!(subjects.pop().unwrap().ends_with(".*"));
+    subjects.sort();
+    assert_eq!(subjects, ["after", "before", "replacement"]);
+
+    // Even without another server to move on to, a lame duck is not given new subscriptions
+    let mut binding = new_binding();
+    let mut announced = info();
+    establish(&mut binding, announced.clone(), now);
+    announced.ldm = Some(true);
+    binding
+        .handle_server_input(ServerCommand::Info(announced), now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::LameDuck));
+    assert_eq!(binding.poll_migrate_timeout(), None);
+    subscribe(&mut binding, "after");
+    assert_eq!(binding.poll_transmit(), None);
+}
+
+#[test]
+fn request_reply() {
+    let now = Instant::now();
+    let timeout = Duration::from_secs(1);
+
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+
+    let request = |binding: &mut NatsBinding| {
+        let response = binding.handle_client_input(
+            ConnectionCommand::Request {
+                subject: "service".into(),
+                payload: Bytes::from_static(b"ping"),
+                headers: None,
+                timeout,
+            },
+            now,
+        );
+        let Ok(Ok(ClientResponse::Requested(id))) = response else {
+            panic!("Expected request");
+        };
+        id
+    };
+
+    // The first request creates the inbox subscription
+    let answered = request(&mut binding);
+    let Some(ClientCommand::Subscribe(inbox)) = binding.poll_transmit() else {
+        panic!("Expected inbox subscription");
+    };
+    assert!(inbox.subject.starts_with("_INBOX.") && inbox.subject.ends_with(".*"));
+    let Some(ClientCommand::Publish(nats_codec::Publish {
+        reply_to: Some(first_reply),
+        ..
+    })) = binding.poll_transmit()
+    else {
+        panic!("Expected request to be published");
+    };
+
+    // Subsequent ones share it
+    let unanswered = request(&mut binding);
+    let Some(ClientCommand::Publish(nats_codec::Publish {
+        reply_to: Some(second_reply),
+        ..
+    })) = binding.poll_transmit()
+    else {
+        panic!("Expected request to be published");
+    };
+    assert_ne!(first_reply, second_reply);
+
+    let no_responders = request(&mut binding);
+    let Some(ClientCommand::Publish(nats_codec::Publish {
+        reply_to: Some(third_reply),
+        ..
+    })) = binding.poll_transmit()
+    else {
+        panic!("Expected request to be published");
+    };
+    assert_eq!(binding.poll_transmit(), None);
+    assert_eq!(binding.poll_request_timeout(), Some(now + timeout));
+
+    binding
+        .handle_server_input(
+            ServerCommand::Msg(nats_codec::Msg {
+                subject: first_reply,
+                sid: inbox.sid.clone(),
+                reply_to: None,
+                bytes: 4,
+                payload: Bytes::from_static(b"pong"),
+            }),
+            now,
+        )
+        .unwrap();
+    let Some((id, Ok(reply))) = binding.poll_reply() else {
+        panic!("Expected reply");
+    };
+    assert_eq!(id, answered);
+    assert_eq!(reply.payload, Bytes::from_static(b"pong"));
+
+    let mut headers = nats_codec::HeaderMap::new();
+    headers.append(nats_codec::STATUS_HEADER, "503");
+    binding
+        .handle_server_input(
+            ServerCommand::HMsg(nats_codec::HMsg {
+                subject: third_reply,
+                sid: inbox.sid,
+                reply_to: None,
+                header_bytes: headers.encoded_len(),
+                total_bytes: headers.encoded_len(),
+                headers,
+                payload: Bytes::new(),
+            }),
+            now,
+        )
+        .unwrap();
+    assert!(matches!(
+        binding.poll_reply(),
+        Some((id, Err(RequestError::NoResponders))) if id == no_responders
+    ));
+
+    binding.handle_timeout(now + timeout).unwrap();
+    assert!(matches!(
+        binding.poll_reply(),
+        Some((id, Err(RequestError::TimedOut))) if id == unanswered
+    ));
+    assert!(binding.poll_reply().is_none());
+    assert_eq!(binding.poll_request_timeout(), None);
+}
+
+#[test]
+fn max_msgs_auto_unsubscribe() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
 
-    let _response = receiver.try_recv().unwrap();
+    let mut subscribe = |max_msgs| {
+        let options = SubscriptionOptions {
+            max_msgs,
+            ..Default::default()
+        };
+        subscribe(&mut binding, "limited", options, now)
+    };
+    let limited = subscribe(NonZeroUsize::new(2));
+    let unlimited = subscribe(None);
+    while binding.poll_transmit().is_some() {}
+
+    let msg = |sid: &Sid| {
+        ServerCommand::Msg(nats_codec::Msg {
+            subject: "limited".into(),
+            sid: sid.clone(),
+            reply_to: None,
+            bytes: 0,
+            payload: Bytes::new(),
+        })
+    };
+
+    binding.handle_server_input(msg(&limited), now).unwrap();
+    assert_eq!(binding.poll_event(), None);
+    binding.handle_server_input(msg(&limited), now).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed {
+            sid: limited.clone()
+        })
+    );
+    // The server unsubscribed on its own, so there is nothing to tell it
+    assert_eq!(binding.poll_transmit(), None);
+
+    // Limiting an existing subscription counts the messages it has already received
+    for _ in 0..2 {
+        binding.handle_server_input(msg(&unlimited), now).unwrap();
+    }
+    binding
+        .handle_client_input(
+            ConnectionCommand::Unsubscribe {
+                sid: unlimited.clone(),
+                max_msgs: NonZeroUsize::new(3),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(_))
+    ));
+    binding.handle_server_input(msg(&unlimited), now).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed {
+            sid: unlimited.clone()
+        })
+    );
+
+    // Everything that was received is still delivered
+    let mut received = vec![];
+    while let Some((sid, _)) = binding.poll_message() {
+        received.push(sid);
+    }
+    assert_eq!(
+        received,
+        [&limited, &limited, &unlimited, &unlimited, &unlimited].map(String::as_str)
+    );
+}
+
+#[test]
+fn slow_consumer() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+
+    let mut subscribe = |policy| {
+        let options = SubscriptionOptions {
+            pending_limits: PendingLimits {
+                messages: 2,
+                bytes: usize::MAX,
+                policy,
+            },
+            ..Default::default()
+        };
+        let sid = subscribe(&mut binding, "slow", options, now);
+        assert!(matches!(
+            binding.poll_transmit(),
+            Some(ClientCommand::Subscribe(_))
+        ));
+        sid
+    };
+    let newest = subscribe(SlowConsumerPolicy::DropNewest);
+    let oldest = subscribe(SlowConsumerPolicy::DropOldest);
+    let failing = subscribe(SlowConsumerPolicy::Error);
+
+    let msg = |sid: &Sid, payload: &'static str| {
+        ServerCommand::Msg(nats_codec::Msg {
+            subject: "slow".into(),
+            sid: sid.clone(),
+            reply_to: None,
+            bytes: payload.len(),
+            payload: Bytes::from_static(payload.as_bytes()),
+        })
+    };
+    for sid in [&newest, &oldest, &failing] {
+        for payload in ["1", "2", "3", "4"] {
+            binding.handle_server_input(msg(sid, payload), now).unwrap();
+        }
+    }
+
+    // Notified once, when the subscriber starts falling behind
+    for sid in [&newest, &oldest, &failing] {
+        assert_eq!(
+            binding.poll_event(),
+            Some(Event::SlowConsumer {
+                sid: sid.clone(),
+                dropped: 1
+            })
+        );
+    }
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed {
+            sid: failing.clone()
+        })
+    );
+    assert_eq!(binding.poll_event(), None);
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+            sid: failing.clone(),
+            max_msgs: None,
+        }))
+    );
+
+    let mut received: Vec<(Sid, Bytes)> = vec![];
+    while let Some((sid, message)) = binding.poll_message() {
+        received.push((sid, message.payload));
+    }
+    let expected: Vec<(Sid, Bytes)> = [
+        (&newest, "1"),
+        (&newest, "2"),
+        (&oldest, "3"),
+        (&oldest, "4"),
+        // What was pending is still delivered
+        (&failing, "1"),
+        (&failing, "2"),
+    ]
+    .into_iter()
+    .map(|(sid, payload)| (sid.clone(), Bytes::from_static(payload.as_bytes())))
+    .collect();
+    assert_eq!(received, expected);
+
+    // Having caught up, the subscriber receives messages again
+    binding.handle_server_input(msg(&newest, "5"), now).unwrap();
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == newest));
+
+    // Messages can be polled per subscription, leaving those of the others pending
+    binding.handle_server_input(msg(&oldest, "6"), now).unwrap();
+    binding.handle_server_input(msg(&newest, "6"), now).unwrap();
+    assert_eq!(
+        binding.poll_message_for(&newest).map(|m| m.payload),
+        Some(Bytes::from_static(b"6"))
+    );
+    assert!(binding.poll_message_for(&newest).is_none());
+    assert_eq!(binding.pending_subscriptions(), vec![oldest.clone()]);
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == oldest));
+    assert!(binding.pending_subscriptions().is_empty());
+}
+
+#[test]
+fn publish_with_headers() {
+    let now = Instant::now();
+
+    let mut headers = nats_codec::HeaderMap::new();
+    headers.append("Trace-Id", "42");
+    let publish = || ConnectionCommand::Publish {
+        subject: "traced".into(),
+        payload: Bytes::from_static(b"Hello World!"),
+        options: PublishOptions {
+            reply_to: Some("replies".into()),
+            headers: Some(headers.clone()),
+            ..Default::default()
+        },
+    };
+
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+
+    binding
+        .handle_client_input(publish(), now)
+        .unwrap()
+        .unwrap();
+    let Some(ClientCommand::HPublish(hpub)) = binding.poll_transmit() else {
+        panic!("Expected HPUB");
+    };
+    assert_eq!(hpub.reply_to.as_deref(), Some("replies"));
+    assert_eq!(hpub.headers.get("Trace-Id").map(|v| v.as_str()), Some("42"));
+
+    // Servers that do not support headers reject them before anything is sent
+    let mut binding = new_binding();
+    let mut no_headers = info();
+    no_headers.headers = false;
+    establish(&mut binding, no_headers, now);
+
+    assert_eq!(
+        binding.handle_client_input(publish(), now),
+        Ok(Err(CommandError::HeadersNotSupported))
+    );
+    assert_eq!(binding.poll_transmit(), None);
+}
+
+#[test]
+fn drain() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+
+    let msg = |sid: &Sid| {
+        ServerCommand::Msg(nats_codec::Msg {
+            subject: "drained".into(),
+            sid: sid.clone(),
+            reply_to: None,
+            bytes: 0,
+            payload: Bytes::new(),
+        })
+    };
+    let publish = || ConnectionCommand::Publish {
+        subject: "drained".into(),
+        payload: Bytes::new(),
+        options: PublishOptions::default(),
+    };
+    let first = subscribe(&mut binding, "drained", SubscriptionOptions::default(), now);
+    let second = subscribe(&mut binding, "drained", SubscriptionOptions::default(), now);
+    while binding.poll_transmit().is_some() {}
+
+    // Messages in flight are delivered until the server confirms there are no more
+    binding
+        .handle_client_input(
+            ConnectionCommand::Drain {
+                sid: Some(first.clone()),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+            sid: first.clone(),
+            max_msgs: None,
+        }))
+    );
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    binding.handle_server_input(msg(&first), now).unwrap();
+    assert_eq!(binding.poll_event(), None);
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed { sid: first.clone() })
+    );
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == first));
+
+    // Draining the connection drains every subscription first, whilst publishing is still possible
+    binding
+        .handle_client_input(ConnectionCommand::Drain { sid: None }, now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+            sid: second.clone(),
+            max_msgs: None,
+        }))
+    );
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    assert_eq!(
+        binding.handle_client_input(
+            ConnectionCommand::Subscribe {
+                subject: "drained".into(),
+                options: SubscriptionOptions::default(),
+            },
+            now
+        ),
+        Ok(Err(CommandError::Draining))
+    );
+    binding
+        .handle_client_input(publish(), now)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+    binding.handle_server_input(msg(&second), now).unwrap();
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed {
+            sid: second.clone()
+        })
+    );
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == second));
+
+    // Then the publishes are flushed
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    assert_eq!(
+        binding.handle_client_input(publish(), now),
+        Ok(Err(CommandError::Draining))
+    );
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::Closed));
+    assert!(matches!(binding.state.conn_state, ConnState::Closed(_)));
+    assert_eq!(binding.poll_timeout(), None);
+}
+
+#[test]
+fn close() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+    let publish = || ConnectionCommand::Publish {
+        subject: "last".into(),
+        payload: Bytes::new(),
+        options: PublishOptions::default(),
+    };
+    binding
+        .handle_client_input(publish(), now)
+        .unwrap()
+        .unwrap();
+
+    // What was enqueued before is still transmitted
+    binding
+        .handle_client_input(ConnectionCommand::Close, now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::Closed));
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+    assert_eq!(binding.poll_transmit(), None);
+
+    assert_eq!(
+        binding.handle_client_input(publish(), now),
+        Ok(Err(CommandError::Closed))
+    );
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), None);
+    assert_eq!(binding.poll_timeout(), None);
+
+    // Publishes buffered until connected are reported as never sent
+    let mut binding = new_binding();
+    binding
+        .handle_client_input(publish(), now)
+        .unwrap()
+        .unwrap();
+    binding
+        .handle_client_input(ConnectionCommand::Close, now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::PublishDiscarded {
+            subject: "last".into(),
+            error: CommandError::Closed,
+        })
+    );
+    assert_eq!(binding.poll_event(), Some(Event::Closed));
+    assert_eq!(binding.poll_transmit(), None);
+}
+
+#[test]
+fn handshake() {
+    let now = Instant::now();
+    let timeout = Duration::from_secs(2);
+    let mut binding = new_binding().with_handshake_options(HandshakeOptions {
+        timeout,
+        max_backlog: 3,
+        max_backlog_bytes: "Hello World!".len(),
+    });
+    let publish = |payload: &'static str| ConnectionCommand::Publish {
+        subject: "early".into(),
+        payload: Bytes::from_static(payload.as_bytes()),
+        options: PublishOptions::default(),
+    };
+
+    // The handshake starts once the transport is established
+    assert_eq!(binding.poll_handshake_timeout(), None);
+    binding.handle_connected(now);
+    assert_eq!(binding.poll_handshake_timeout(), Some(now + timeout));
+    assert_eq!(binding.poll_timeout(), Some(now + timeout));
+
+    // Bounded by bytes...
+    binding
+        .handle_client_input(publish("Hello"), now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.handle_client_input(publish("Hello World!"), now),
+        Ok(Err(CommandError::BacklogFull))
+    );
+    binding
+        .handle_client_input(publish("World!"), now)
+        .unwrap()
+        .unwrap();
+
+    // ...and by commands
+    binding
+        .handle_client_input(ConnectionCommand::Flush, now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.handle_client_input(ConnectionCommand::Flush, now),
+        Ok(Err(CommandError::BacklogFull))
+    );
+    assert_eq!(
+        binding.handle_client_input(publish(""), now),
+        Ok(Err(CommandError::BacklogFull))
+    );
+
+    // The server never sends INFO
+    binding
+        .handle_timeout(now + timeout - Duration::from_millis(1))
+        .unwrap();
+    assert_eq!(binding.poll_event(), None);
+    assert_eq!(
+        binding.handle_timeout(now + timeout),
+        Err(NatsProtocolError::ConnectionLost)
+    );
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+    assert_eq!(binding.poll_timeout(), None);
+
+    // Nor does it answer the `PING` following `CONNECT`
+    let mut binding = new_binding().with_handshake_options(HandshakeOptions {
+        timeout,
+        ..Default::default()
+    });
+    binding.handle_connected(now);
+    binding
+        .handle_client_input(publish("Hello"), now)
+        .unwrap()
+        .unwrap();
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Connect(_))
+    ));
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    // `+OK` and `PING` do not establish the connection
+    binding.handle_server_input(ServerCommand::Ok, now).unwrap();
+    binding
+        .handle_server_input(ServerCommand::Ping, now)
+        .unwrap();
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Pong));
+    assert_eq!(binding.poll_transmit(), None);
+    assert_eq!(binding.poll_event(), None);
+    assert_eq!(binding.poll_handshake_timeout(), Some(now + timeout));
+    assert_eq!(
+        binding.handle_timeout(now + timeout),
+        Err(NatsProtocolError::ConnectionLost)
+    );
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+
+    // The initial connection is given up on once the server rejects it, even if reconnecting is enabled
+    let mut binding = new_binding().with_reconnect(ReconnectOptions::default());
+    binding.add_server("localhost".parse().unwrap());
+    binding.handle_connected(now);
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    assert_eq!(
+        binding.handle_server_input(ServerCommand::Err("Authorization Violation".into()), now),
+        Err(NatsProtocolError::AuthorizationFailed(
+            "Authorization Violation".into()
+        ))
+    );
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::AuthorizationFailed {
+            error: "Authorization Violation".into()
+        })
+    );
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::ConnectRejected {
+            error: "Authorization Violation".into()
+        })
+    );
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::ConnectionLost
+    ));
+    let mut binding = new_binding();
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    assert_eq!(
+        binding.handle_server_input(
+            ServerCommand::Err("Maximum Connections Exceeded".into()),
+            now
+        ),
+        Err(NatsProtocolError::ConnectRejected(
+            "Maximum Connections Exceeded".into()
+        ))
+    );
+
+    // Later connections are retried, since the server may merely be misconfigured for the moment
+    let mut binding = new_binding().with_reconnect(ReconnectOptions::default());
+    binding.add_server("localhost".parse().unwrap());
+    establish(&mut binding, info(), now);
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    binding
+        .handle_timeout(now + Duration::from_secs(10))
+        .unwrap();
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::Reconnect { .. })
+    ));
+    binding.handle_connected(now + Duration::from_secs(10));
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now + Duration::from_secs(10))
+        .unwrap();
+    binding
+        .handle_server_input(
+            ServerCommand::Err("Maximum Connections Exceeded".into()),
+            now,
+        )
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::ConnectRejected {
+            error: "Maximum Connections Exceeded".into()
+        })
+    );
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::Reconnecting(_)
+    ));
+}
+
+#[test]
+fn server_errors() {
+    let now = Instant::now();
+
+    let mut binding = new_binding();
+    assert_eq!(
+        binding.handle_server_input(ServerCommand::Ping, now),
+        Err(NatsProtocolError::NotInfoReceived)
+    );
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::ProtocolError { .. })
+    ));
+    assert!(matches!(
+        binding.state.conn_state,
+        ConnState::NotInfoReceived
+    ));
+    // Nothing is accepted anymore, rather than being discarded later on
+    assert_eq!(
+        binding.handle_client_input(ConnectionCommand::Flush, now),
+        Err(NatsProtocolError::NotInfoReceived)
+    );
+
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+    binding
+        .handle_server_input(ServerCommand::Err("Unknown Protocol Operation".into()), now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), None);
+    binding
+        .handle_server_input(ServerCommand::Err("Authorization Violation".into()), now)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::AuthorizationFailed {
+            error: "Authorization Violation".into()
+        })
+    );
+}
+
+#[test]
+fn info_updates() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    assert_eq!(binding.server_info(), None);
+
+    let mut announced = info();
+    announced.cluster = Some("us-south".into());
+    announced.connect_urls = Some(vec!["first:4222".into()]);
+    establish(&mut binding, announced.clone(), now);
+    assert_eq!(binding.server_info(), Some(&*announced));
+
+    let mut update = info();
+    update.headers = false;
+    update.max_payload = 1024;
+    update.connect_urls = Some(vec!["first:4222".into(), "second:4222".into()]);
+    binding
+        .handle_server_input(ServerCommand::Info(update), now)
+        .unwrap();
+
+    let merged = binding.server_info().unwrap();
+    assert!(!merged.headers);
+    assert_eq!(merged.max_payload, 1024);
+    assert_eq!(merged.connect_urls.as_ref().unwrap().len(), 2);
+    // Left out of the update, so it remains as announced
+    assert_eq!(merged.cluster.as_deref(), Some("us-south"));
+
+    let mut headers = nats_codec::HeaderMap::new();
+    headers.append("Trace-Id", "42");
+    assert_eq!(
+        binding.handle_client_input(
+            ConnectionCommand::Publish {
+                subject: "traced".into(),
+                payload: Bytes::new(),
+                options: PublishOptions {
+                    headers: Some(headers),
+                    ..Default::default()
+                },
+            },
+            now
+        ),
+        Ok(Err(CommandError::HeadersNotSupported))
+    );
+}
+
+#[test]
+fn statistics() {
+    let now = Instant::now();
+    let rtt = Duration::from_millis(5);
+    let mut binding = new_binding().with_reconnect(ReconnectOptions {
+        backoff: Duration::ZERO,
+        jitter: Duration::ZERO,
+        ..Default::default()
+    });
+    binding.add_server("localhost".parse().unwrap());
+    establish(&mut binding, info(), now);
+    assert_eq!(
+        binding.statistics(),
+        Statistics {
+            rtt: Some(Duration::ZERO),
+            ..Default::default()
+        }
+    );
+
+    let sid = subscribe(
+        &mut binding,
+        "counted",
+        SubscriptionOptions {
+            pending_limits: PendingLimits {
+                messages: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        },
+        now,
+    );
+    binding
+        .handle_client_input(
+            ConnectionCommand::Publish {
+                subject: "counted".into(),
+                payload: Bytes::from_static(b"Hello World!"),
+                options: PublishOptions::default(),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    binding.handle_timeout(now).unwrap();
+    while binding.poll_transmit().is_some() {}
+
+    for _ in 0..2 {
+        binding
+            .handle_server_input(
+                ServerCommand::Msg(nats_codec::Msg {
+                    subject: "counted".into(),
+                    sid: sid.clone(),
+                    reply_to: None,
+                    bytes: 3,
+                    payload: Bytes::from_static(b"abc"),
+                }),
+                now,
+            )
+            .unwrap();
+    }
+    binding
+        .handle_server_input(ServerCommand::Err("Unknown Protocol Operation".into()), now)
+        .unwrap();
+
+    // Reconnecting keeps the counters, and measures the round-trip time anew
+    binding.handle_disconnected(now);
+    binding.handle_timeout(now).unwrap();
+    binding.handle_connected(now);
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    while binding.poll_transmit().is_some() {}
+    binding
+        .handle_server_input(ServerCommand::Pong, now + rtt)
+        .unwrap();
+
+    let statistics = binding.statistics();
+    assert_eq!(
+        statistics,
+        Statistics {
+            in_msgs: 2,
+            in_bytes: 6,
+            out_msgs: 1,
+            out_bytes: "Hello World!".len() as u64,
+            reconnects: 1,
+            errors: 1,
+            rtt: Some(rtt),
+            subscriptions: [(
+                sid,
+                SubscriptionStatistics {
+                    subject: "counted".into(),
+                    delivered: 2,
+                    dropped: 1,
+                    pending_messages: 1,
+                    pending_bytes: 3,
+                }
+            )]
+            .into(),
+        }
+    );
+}
+
+#[test]
+fn max_payload() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    let mut info = info();
+    info.max_payload = "Hello World!".len();
+    establish(&mut binding, info.clone(), now);
+
+    let mut headers = nats_codec::HeaderMap::new();
+    headers.append("Trace-Id", "42");
+    let publish = |headers| ConnectionCommand::Publish {
+        subject: "limited".into(),
+        payload: Bytes::from_static(b"Hello World!"),
+        options: PublishOptions {
+            headers,
+            ..Default::default()
+        },
+    };
+
+    binding
+        .handle_client_input(publish(None), now)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+
+    // Headers count towards the payload
+    let size = "Hello World!".len() + headers.encoded_len();
+    assert_eq!(
+        binding.handle_client_input(publish(Some(headers.clone())), now),
+        Ok(Err(CommandError::MaxPayloadExceeded {
+            size,
+            max_payload: "Hello World!".len()
+        }))
+    );
+    assert!(matches!(
+        binding.handle_client_input(
+            ConnectionCommand::Request {
+                subject: "limited".into(),
+                payload: Bytes::from_static(b"Hello World!!"),
+                headers: None,
+                timeout: Duration::from_secs(1),
+            },
+            now
+        ),
+        Ok(Err(CommandError::MaxPayloadExceeded { .. }))
+    ));
+    assert_eq!(binding.poll_transmit(), None);
+
+    // The server may announce a different limit later on
+    info.max_payload = size;
+    binding
+        .handle_server_input(ServerCommand::Info(info.clone()), now)
+        .unwrap();
+    binding
+        .handle_client_input(publish(Some(headers.clone())), now)
+        .unwrap()
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::HPublish(_))
+    ));
+
+    // Publishes issued before the limit is known are discarded once it turns out to be exceeded
+    let mut binding = new_binding();
+    binding
+        .handle_client_input(publish(Some(headers.clone())), now)
+        .unwrap()
+        .unwrap();
+    binding
+        .handle_client_input(publish(None), now)
+        .unwrap()
+        .unwrap();
+    let request = binding.handle_client_input(
+        ConnectionCommand::Request {
+            subject: "limited".into(),
+            payload: Bytes::from_static(b"Hello World!"),
+            headers: Some(headers),
+            timeout: Duration::from_secs(1),
+        },
+        now,
+    );
+    let Ok(Ok(ClientResponse::Requested(id))) = request else {
+        panic!("Expected request");
+    };
+    info.max_payload = "Hello World!".len();
+    establish(&mut binding, info, now);
+    let error = CommandError::MaxPayloadExceeded {
+        size,
+        max_payload: "Hello World!".len(),
+    };
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::PublishDiscarded {
+            subject: "limited".into(),
+            error: error.clone(),
+        })
+    );
+    assert_eq!(binding.poll_event(), None);
+    // The inbox subscription of the request is sent regardless
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Subscribe(_))
+    ));
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+    assert_eq!(binding.poll_transmit(), None);
+
+    // Requests fail right away, rather than timing out
+    assert!(binding.outstanding_requests().is_empty());
+    let Some((replied, Err(RequestError::Rejected(rejected)))) = binding.poll_reply() else {
+        panic!("Expected rejection");
+    };
+    assert_eq!((replied, rejected), (id, error));
+}
+
+#[test]
+fn acknowledged_publish() {
+    let now = Instant::now();
+    let backoff = Duration::from_secs(1);
+    let publish = |acknowledge| ConnectionCommand::Publish {
+        subject: "critical".into(),
+        payload: Bytes::from_static(b"Hello World!"),
+        options: PublishOptions {
+            acknowledge,
+            ..Default::default()
+        },
+    };
+
+    // Without verbose mode, the server never acknowledges anything
+    let mut binding = new_binding();
+    assert_eq!(
+        binding.handle_client_input(publish(true), now),
+        Ok(Err(CommandError::NotVerbose))
+    );
+
+    let mut binding = new_binding()
+        .with_connect_options(ConnectOptions {
+            verbose: true,
+            ..Default::default()
+        })
+        .with_reconnect(ReconnectOptions {
+            backoff,
+            jitter: Duration::ZERO,
+            ..Default::default()
+        });
+    binding.add_server("localhost".parse().unwrap());
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Connect(nats_codec::Connect {
+            verbose: true,
+            ..
+        }))
+    ));
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    // `CONNECT` is acknowledged during the handshake
+    binding.handle_server_input(ServerCommand::Ok, now).unwrap();
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::Connected));
+
+    let acknowledged = |binding: &mut NatsBinding| {
+        let response = binding.handle_client_input(publish(true), now);
+        let Ok(Ok(ClientResponse::Acknowledging(id))) = response else {
+            panic!("Expected acknowledgement");
+        };
+        id
+    };
+    let confirmed = acknowledged(&mut binding);
+    assert_eq!(
+        binding.handle_client_input(publish(false), now),
+        Ok(Ok(ClientResponse::Accepted))
+    );
+    let failed = acknowledged(&mut binding);
+    let lost = acknowledged(&mut binding);
+    for _ in 0..4 {
+        assert!(matches!(
+            binding.poll_transmit(),
+            Some(ClientCommand::Publish(_))
+        ));
+    }
+    let salvaged = acknowledged(&mut binding);
+
+    // Answers arrive in the order the commands were sent
+    binding.handle_server_input(ServerCommand::Ok, now).unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Acknowledged {
+            id: confirmed,
+            result: Ok(())
+        })
+    );
+    binding.handle_server_input(ServerCommand::Ok, now).unwrap();
+    assert_eq!(binding.poll_event(), None);
+    binding
+        .handle_server_input(
+            ServerCommand::Err("Permissions Violation for Publish to critical".into()),
+            now,
+        )
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Acknowledged {
+            id: failed,
+            result: Err(AckError::Server(
+                "Permissions Violation for Publish to critical".into()
+            ))
+        })
+    );
+
+    // Sent, but never answered
+    binding.handle_disconnected(now);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Acknowledged {
+            id: lost,
+            result: Err(AckError::ConnectionLost)
+        })
+    );
+
+    // Not sent yet, so it is acknowledged by the next server
+    binding.handle_timeout(now + backoff).unwrap();
+    assert!(matches!(
+        binding.poll_event(),
+        Some(Event::Reconnect { .. })
+    ));
+    binding.handle_connected(now + backoff);
+    establish(&mut binding, info(), now + backoff);
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Publish(_))
+    ));
+    binding
+        .handle_server_input(ServerCommand::Ok, now + backoff)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Acknowledged {
+            id: salvaged,
+            result: Ok(())
+        })
+    );
+}
+
+#[test]
+fn multiplexing() {
+    let now = Instant::now();
+    let backoff = Duration::from_secs(1);
+    let mut binding = new_binding()
+        .with_reconnect(ReconnectOptions {
+            backoff,
+            jitter: Duration::ZERO,
+            ..Default::default()
+        })
+        .with_multiplexing();
+    binding.add_server("localhost".parse().unwrap());
+    establish(&mut binding, info(), now);
+
+    let first = subscribe(
+        &mut binding,
+        "prices.>",
+        SubscriptionOptions::default(),
+        now,
+    );
+    let Some(ClientCommand::Subscribe(shared)) = binding.poll_transmit() else {
+        panic!("Expected shared subscription");
+    };
+    assert_eq!(shared.subject, "prices.>");
+    assert_ne!(shared.sid, first);
+
+    // Joins the existing server subscription, limited by the binding rather than the server
+    let limited = subscribe(
+        &mut binding,
+        "prices.>",
+        SubscriptionOptions {
+            max_msgs: NonZeroUsize::new(1),
+            ..Default::default()
+        },
+        now,
+    );
+    assert_eq!(binding.poll_transmit(), None);
+    // As do subjects it matches
+    let narrow = subscribe(
+        &mut binding,
+        "prices.usd",
+        SubscriptionOptions::default(),
+        now,
+    );
+    assert_eq!(binding.poll_transmit(), None);
+    // Queue groups are left alone
+    subscribe(
+        &mut binding,
+        "prices.>",
+        SubscriptionOptions {
+            queue_group: Some("workers".into()),
+            ..Default::default()
+        },
+        now,
+    );
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Subscribe(nats_codec::Subscribe {
+            queue_group: Some(_),
+            ..
+        }))
+    ));
+
+    // Fanned out to every subscriber of the server subscription
+    binding
+        .handle_server_input(
+            ServerCommand::Msg(nats_codec::Msg {
+                subject: "prices.eur".into(),
+                sid: shared.sid.clone(),
+                reply_to: None,
+                bytes: 0,
+                payload: Bytes::new(),
+            }),
+            now,
+        )
+        .unwrap();
+    let mut received: Vec<Sid> = vec![];
+    while let Some((sid, _)) = binding.poll_message() {
+        received.push(sid);
+    }
+    received.sort();
+    let mut expected = vec![first.clone(), limited.clone()];
+    expected.sort();
+    assert_eq!(received, expected);
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed {
+            sid: limited.clone()
+        })
+    );
+    assert_eq!(binding.poll_transmit(), None);
+
+    // Replayed once after reconnecting, no matter how many subscribers share it
+    let second = subscribe(
+        &mut binding,
+        "prices.>",
+        SubscriptionOptions::default(),
+        now,
+    );
+    binding.handle_disconnected(now);
+    binding.handle_timeout(now + backoff).unwrap();
+    binding.handle_connected(now + backoff);
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now + backoff)
+        .unwrap();
+    binding
+        .handle_server_input(ServerCommand::Pong, now + backoff)
+        .unwrap();
+    let mut transmits = vec![];
+    while let Some(transmit) = binding.poll_transmit() {
+        transmits.push(transmit);
+    }
+    let replayed: Vec<&str> = transmits
+        .iter()
+        .filter_map(|transmit| match transmit {
+            ClientCommand::Subscribe(subscribe) if subscribe.sid == shared.sid => {
+                Some(subscribe.subject.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    assert_eq!(replayed, ["prices.>"]);
+    binding
+        .handle_client_input(
+            ConnectionCommand::Unsubscribe {
+                sid: narrow,
+                max_msgs: None,
+            },
+            now + backoff,
+        )
+        .unwrap()
+        .unwrap();
+
+    // Unsubscribed on the server once the last subscriber is gone
+    for (sid, expected) in [(first, None), (second, Some(shared.sid))] {
+        binding
+            .handle_client_input(
+                ConnectionCommand::Unsubscribe {
+                    sid,
+                    max_msgs: None,
+                },
+                now + backoff,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            binding.poll_transmit(),
+            expected.map(|sid| ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+                sid,
+                max_msgs: None
+            }))
+        );
+    }
+}
+
+#[test]
+fn seeded_ids() {
+    let now = Instant::now();
+    let inbox = |seed| {
+        let mut binding = new_binding().with_id_generator(SeededIdGenerator::new(seed));
+        establish(&mut binding, info(), now);
+
+        binding
+            .handle_client_input(
+                ConnectionCommand::Request {
+                    subject: "service".into(),
+                    payload: Bytes::new(),
+                    headers: None,
+                    timeout: Duration::from_secs(1),
+                },
+                now,
+            )
+            .unwrap()
+            .unwrap();
+        let Some(ClientCommand::Subscribe(inbox)) = binding.poll_transmit() else {
+            panic!("Expected inbox subscription");
+        };
+        inbox
+    };
+
+    // The same seed always results in the same inbox, in the format of the Go client
+    let inbox_subject = inbox(42).subject;
+    assert_eq!(inbox_subject, inbox(42).subject);
+    assert_ne!(inbox_subject, inbox(7).subject);
+    let nuid = inbox_subject
+        .strip_prefix("_INBOX.")
+        .and_then(|subject| subject.strip_suffix(".*"))
+        .unwrap();
+    assert_eq!(nuid.len(), 22);
+    assert!(nuid.chars().all(|c| c.is_ascii_alphanumeric()));
+    assert_eq!(inbox(42).sid, "1");
+}
+
+#[test]
+fn introspection() {
+    let now = Instant::now();
+    let timeout = Duration::from_secs(1);
+    let mut binding = new_binding().with_reconnect(ReconnectOptions {
+        backoff: Duration::ZERO,
+        jitter: Duration::ZERO,
+        ..Default::default()
+    });
+    binding.add_server("localhost".parse().unwrap());
+    assert_eq!(binding.lifecycle(), Lifecycle::AwaitingInfo);
+
+    let sid = subscribe(
+        &mut binding,
+        "inspected",
+        SubscriptionOptions {
+            max_msgs: NonZeroUsize::new(3),
+            queue_group: Some("group".into()),
+            ..Default::default()
+        },
+        now,
+    );
+    // Held back until connected
+    assert_eq!(binding.pending_transmits(), 0);
+
+    binding
+        .handle_server_input(ServerCommand::Info(info()), now)
+        .unwrap();
+    assert_eq!(binding.lifecycle(), Lifecycle::AwaitingPong);
+    assert_eq!(binding.pending_transmits(), 2);
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(binding.lifecycle(), Lifecycle::Connected);
+    // `CONNECT`, `PING`, `SUB` and `UNSUB`
+    assert_eq!(binding.pending_transmits(), 4);
+    while binding.poll_transmit().is_some() {}
+    assert_eq!(binding.pending_transmits(), 0);
+
+    binding
+        .handle_server_input(
+            ServerCommand::Msg(nats_codec::Msg {
+                subject: "inspected".into(),
+                sid: sid.clone(),
+                reply_to: None,
+                bytes: 0,
+                payload: Bytes::new(),
+            }),
+            now,
+        )
+        .unwrap();
+    assert_eq!(
+        binding.subscriptions(),
+        [SubscriptionInfo {
+            sid,
+            subject: "inspected".into(),
+            queue_group: Some("group".into()),
+            delivered: 1,
+            remaining: Some(2),
+        }]
+    );
+
+    let mut request = |at: Instant| {
+        let response = binding.handle_client_input(
+            ConnectionCommand::Request {
+                subject: "service".into(),
+                payload: Bytes::new(),
+                headers: None,
+                timeout,
+            },
+            at,
+        );
+        let Ok(Ok(ClientResponse::Requested(id))) = response else {
+            panic!("Expected request");
+        };
+        id
+    };
+    let later = request(now + timeout);
+    let earlier = request(now);
+    assert_eq!(
+        binding.outstanding_requests(),
+        [
+            OutstandingRequest {
+                id: earlier,
+                deadline: now + timeout
+            },
+            OutstandingRequest {
+                id: later,
+                deadline: now + timeout * 2
+            }
+        ]
+    );
+
+    binding.handle_disconnected(now);
+    assert_eq!(binding.lifecycle(), Lifecycle::Reconnecting { attempt: 0 });
+    binding.handle_timeout(now).unwrap();
+    assert_eq!(binding.lifecycle(), Lifecycle::Reconnecting { attempt: 1 });
+    binding
+        .handle_client_input(ConnectionCommand::Close, now)
+        .unwrap()
+        .unwrap();
+    assert_eq!(binding.lifecycle(), Lifecycle::Closed);
+}
+
+#[test]
+fn connect_options() {
+    let now = Instant::now();
+    let connect = |options: ConnectOptions| {
+        let mut binding = new_binding().with_connect_options(options);
+        binding
+            .handle_server_input(ServerCommand::Info(info()), now)
+            .unwrap();
+        let Some(ClientCommand::Connect(connect)) = binding.poll_transmit() else {
+            panic!("Expected CONNECT");
+        };
+        serde_json::to_string(&connect).unwrap()
+    };
+
+    for (no_echo, no_responders, json) in [
+        (
+            false,
+            false,
+            r#"{"verbose":false,"pedantic":true,"tls_required":false,"auth_token":null,"user":null,"pass":null,"name":null,"lang":"Rust","version":"1.0","protocol":1,"echo":true,"sig":null,"jwt":null,"no_responders":false,"headers":true,"nkey":null}"#,
+        ),
+        (
+            true,
+            false,
+            r#"{"verbose":false,"pedantic":true,"tls_required":false,"auth_token":null,"user":null,"pass":null,"name":null,"lang":"Rust","version":"1.0","protocol":1,"echo":false,"sig":null,"jwt":null,"no_responders":false,"headers":true,"nkey":null}"#,
+        ),
+        (
+            false,
+            true,
+            r#"{"verbose":false,"pedantic":true,"tls_required":false,"auth_token":null,"user":null,"pass":null,"name":null,"lang":"Rust","version":"1.0","protocol":1,"echo":true,"sig":null,"jwt":null,"no_responders":true,"headers":true,"nkey":null}"#,
+        ),
+        (
+            true,
+            true,
+            r#"{"verbose":false,"pedantic":true,"tls_required":false,"auth_token":null,"user":null,"pass":null,"name":null,"lang":"Rust","version":"1.0","protocol":1,"echo":false,"sig":null,"jwt":null,"no_responders":true,"headers":true,"nkey":null}"#,
+        ),
+    ] {
+        let options = ConnectOptions {
+            no_echo,
+            no_responders,
+            ..Default::default()
+        };
+        assert_eq!(connect(options), json);
+    }
+
+    // Servers that predate protocol version 1 always echo
+    let mut original = info();
+    original.proto = 0;
+    let mut binding = new_binding().with_connect_options(ConnectOptions {
+        no_echo: true,
+        ..Default::default()
+    });
+    assert_eq!(
+        binding.handle_server_input(ServerCommand::Info(original.clone()), now),
+        Err(NatsProtocolError::EchoNotSupported)
+    );
+    assert_eq!(binding.poll_transmit(), None);
+    assert_eq!(binding.poll_event(), Some(Event::Disconnected));
+    assert_eq!(binding.poll_event(), Some(Event::ConnectionLost));
+    assert_eq!(
+        binding.handle_timeout(now),
+        Err(NatsProtocolError::ConnectionLost)
+    );
+
+    // Unless asked not to
+    let mut binding = new_binding();
+    establish(&mut binding, original, now);
+}
+
+#[test]
+fn resubscribe() {
+    let now = Instant::now();
+    let mut binding = new_binding();
+    establish(&mut binding, info(), now);
+
+    let msg = |subject: &str, sid: &Sid| {
+        ServerCommand::Msg(nats_codec::Msg {
+            subject: subject.into(),
+            sid: sid.clone(),
+            reply_to: None,
+            bytes: 0,
+            payload: Bytes::new(),
+        })
+    };
+    let old = subscribe(&mut binding, "old", SubscriptionOptions::default(), now);
+    while binding.poll_transmit().is_some() {}
+
+    // The new subscription is in place before the old one ends, so nothing is missed in between
+    let Ok(Ok(ClientResponse::Subscribed(SubscribeResponse { sid: new, .. }))) = binding
+        .handle_client_input(
+            ConnectionCommand::Resubscribe {
+                sid: old.clone(),
+                subject: "new".into(),
+                options: SubscriptionOptions {
+                    queue_group: Some("workers".into()),
+                    ..Default::default()
+                },
+            },
+            now,
+        )
+    else {
+        panic!("Expected subscription");
+    };
+    assert_ne!(new, old);
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Subscribe(nats_codec::Subscribe {
+            subject: "new".into(),
+            queue_group: Some("workers".into()),
+            sid: new.clone(),
+        }))
+    );
+    assert_eq!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+            sid: old.clone(),
+            max_msgs: None,
+        }))
+    );
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+
+    // Messages in flight for the old subscription are delivered until the server confirms there are no more
+    binding.handle_server_input(msg("new", &new), now).unwrap();
+    binding.handle_server_input(msg("old", &old), now).unwrap();
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(
+        binding.poll_event(),
+        Some(Event::Unsubscribed { sid: old.clone() })
+    );
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == new));
+    assert!(matches!(binding.poll_message(), Some((sid, _)) if sid == old));
+    assert_eq!(
+        binding
+            .subscriptions()
+            .into_iter()
+            .map(|subscription| subscription.sid)
+            .collect::<Vec<_>>(),
+        vec![new.clone()]
+    );
+
+    // Subscriptions that are gone, or on their way out, cannot be replaced
+    let resubscribe = |sid: &Sid| ConnectionCommand::Resubscribe {
+        sid: sid.clone(),
+        subject: "newer".into(),
+        options: SubscriptionOptions::default(),
+    };
+    assert_eq!(
+        binding.handle_client_input(resubscribe(&old), now),
+        Ok(Err(CommandError::UnknownSubscription(old.clone())))
+    );
+    binding
+        .handle_client_input(
+            ConnectionCommand::Drain {
+                sid: Some(new.clone()),
+            },
+            now,
+        )
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        binding.handle_client_input(resubscribe(&new), now),
+        Ok(Err(CommandError::UnknownSubscription(new.clone())))
+    );
+}
+
+/// A binding that is in no hurry to ping the server.
+#[cfg(test)]
+fn new_binding() -> NatsBinding {
+    NatsBinding::new(Timeouts {
+        ping_interval: Duration::from_secs(10),
+        pong_delay: Duration::from_secs(10),
+        keep_alive: Duration::from_secs(10),
+    })
+}
+
+/// Answers `CONNECT` and the `PING` that follows it, establishing the connection.
+/// Events issued before must have been polled already.
+#[cfg(test)]
+fn establish(binding: &mut NatsBinding, info: Box<nats_codec::Info>, now: Instant) {
+    binding
+        .handle_server_input(ServerCommand::Info(info), now)
+        .unwrap();
+    assert!(matches!(
+        binding.poll_transmit(),
+        Some(ClientCommand::Connect(_))
+    ));
+    assert_eq!(binding.poll_transmit(), Some(ClientCommand::Ping));
+    binding
+        .handle_server_input(ServerCommand::Pong, now)
+        .unwrap();
+    assert_eq!(binding.poll_event(), Some(Event::Connected));
+}
+
+/// Subscribes to `subject`, answering with the sid the subscription was given.
+#[cfg(test)]
+fn subscribe(
+    binding: &mut NatsBinding,
+    subject: &str,
+    options: SubscriptionOptions,
+    now: Instant,
+) -> Sid {
+    let subscribe = ConnectionCommand::Subscribe {
+        subject: subject.into(),
+        options,
+    };
+    let Ok(Ok(ClientResponse::Subscribed(SubscribeResponse { sid, .. }))) =
+        binding.handle_client_input(subscribe, now)
+    else {
+        panic!("Expected subscription");
+    };
+    sid
 }
 
 #[cfg(test)]
@@ -448,9 +3498,13 @@ struct StepExpectations {
 }
 
 #[cfg(test)]
-fn step(binding: &mut NatsBinding, tick: Instant, expectations: StepExpectations) {
+fn step(
+    binding: &mut NatsBinding,
+    tick: Instant,
+    expectations: StepExpectations,
+) -> Result<(), NatsProtocolError> {
     assert_eq!(
-        binding.poll_send_ping_timeout(tick),
+        binding.poll_send_ping_timeout(),
         expectations.poll_send_ping,
         "Wrong PING timeout"
     );
@@ -465,9 +3519,7 @@ fn step(binding: &mut NatsBinding, tick: Instant, expectations: StepExpectations
         "Wrong Keep-Alive timeout!"
     );
 
-    binding.handle_send_ping_timeout(tick);
-    binding.handle_send_pong_timeout(tick);
-    binding.handle_keep_alive_timeout(tick);
+    binding.handle_timeout(tick)
 
     /*
     let mut msgs = expectations.enqueued.into_iter();
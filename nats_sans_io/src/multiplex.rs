@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+
+use crate::Sid;
+
+/// Maps subjects to values, one node per `.`-separated token.
+/// Wildcards are stored as ordinary tokens, so that every distinct pattern has a node of its own,
+/// and expanded by [Self::matches].
+#[derive(Debug)]
+pub struct SubjectTrie<T> {
+    value: Option<T>,
+    children: HashMap<String, SubjectTrie<T>>,
+}
+
+impl<T> Default for SubjectTrie<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<T> SubjectTrie<T> {
+    /// Values of the patterns that match `subject`. If `subject` contains wildcards itself,
+    /// those of the patterns that match every subject it matches.
+    pub fn matches(&self, subject: &str) -> Vec<&T> {
+        let tokens: Vec<&str> = subject.split('.').collect();
+        let mut found = vec![];
+        self.collect(&tokens, &mut found);
+        found
+    }
+
+    fn collect<'a>(&'a self, tokens: &[&str], found: &mut Vec<&'a T>) {
+        let Some((token, rest)) = tokens.split_first() else {
+            found.extend(self.value.as_ref());
+            return;
+        };
+
+        // Stands for one or more tokens, whatever they are
+        if let Some(value) = self
+            .children
+            .get(">")
+            .and_then(|child| child.value.as_ref())
+        {
+            found.push(value);
+        }
+        if *token == ">" {
+            return;
+        }
+        if let Some(child) = self.children.get("*") {
+            child.collect(rest, found);
+        }
+        if *token != "*" {
+            if let Some(child) = self.children.get(*token) {
+                child.collect(rest, found);
+            }
+        }
+    }
+
+    /// Returns the value that was previously stored for `subject`.
+    pub fn insert(&mut self, subject: &str, value: T) -> Option<T> {
+        subject
+            .split('.')
+            .fold(self, |node, token| {
+                node.children.entry(token.into()).or_default()
+            })
+            .value
+            .replace(value)
+    }
+
+    /// Also prunes the nodes that are left without values.
+    pub fn remove(&mut self, subject: &str) -> Option<T> {
+        let tokens: Vec<&str> = subject.split('.').collect();
+        self.remove_tokens(&tokens)
+    }
+
+    fn remove_tokens(&mut self, tokens: &[&str]) -> Option<T> {
+        let Some((token, rest)) = tokens.split_first() else {
+            return self.value.take();
+        };
+
+        let child = self.children.get_mut(*token)?;
+        let value = child.remove_tokens(rest);
+        if child.value.is_none() && child.children.is_empty() {
+            self.children.remove(*token);
+        }
+        value
+    }
+}
+
+/// Whether the subject `pattern` matches `subject`, which contains no wildcards.
+pub fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(actual)) if token == actual => {}
+            _ => return false,
+        }
+    }
+    subject.next().is_none()
+}
+
+/// Shares one server subscription between every local subscriber whose subject it matches,
+/// see [NatsBinding::with_multiplexing](crate::NatsBinding::with_multiplexing).
+#[derive(Debug, Default)]
+pub struct Multiplexer {
+    /// Server subscription of each subject that new subscribers may join,
+    /// if it matches everything they subscribe to.
+    patterns: SubjectTrie<Sid>,
+    shared: HashMap<Sid, Shared>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    subject: String,
+    /// Local subscribers, in the order they joined.
+    subscribers: Vec<Sid>,
+    /// Whether the server was already told to unsubscribe, e.g. because every subscriber is being drained.
+    unsubscribed: bool,
+}
+
+impl Multiplexer {
+    /// Attaches the local subscriber `sid` to a server subscription that matches everything `subject` does,
+    /// which is created with the SID returned by `next_sid` unless there is one already.
+    /// Returns the server subscription's SID, and whether it was created, i.e. the server has to be told about it.
+    pub fn attach(
+        &mut self,
+        subject: &str,
+        sid: Sid,
+        next_sid: impl FnOnce() -> Sid,
+    ) -> (Sid, bool) {
+        let joined = self
+            .patterns
+            .matches(subject)
+            .into_iter()
+            .find(|server| self.shared.contains_key(*server))
+            .cloned();
+        if let Some(server) = joined {
+            if let Some(shared) = self.shared.get_mut(&server) {
+                shared.subscribers.push(sid);
+                return (server, false);
+            }
+        }
+
+        let server = next_sid();
+        self.patterns.insert(subject, server.clone());
+        self.shared.insert(
+            server.clone(),
+            Shared {
+                subject: subject.into(),
+                subscribers: vec![sid],
+                unsubscribed: false,
+            },
+        );
+        (server, true)
+    }
+
+    /// Subject the server subscription `server` was made with.
+    pub fn subject(&self, server: &str) -> Option<&str> {
+        self.shared
+            .get(server)
+            .map(|shared| shared.subject.as_str())
+    }
+
+    /// Local subscribers of the server subscription `server`; `None` if it is not shared.
+    /// Each of them only receives the messages its own subject matches.
+    pub fn subscribers(&self, server: &str) -> Option<&[Sid]> {
+        self.shared
+            .get(server)
+            .map(|shared| shared.subscribers.as_slice())
+    }
+
+    /// Detaches the local subscriber `sid` from `server`.
+    /// Returns whether it was the last one, and the server still has to be told to unsubscribe.
+    pub fn detach(&mut self, server: &str, sid: &str) -> bool {
+        let Some(shared) = self.shared.get_mut(server) else {
+            return false;
+        };
+        shared.subscribers.retain(|subscriber| subscriber != sid);
+        if !shared.subscribers.is_empty() {
+            return false;
+        }
+
+        let shared = self.shared.remove(server).unwrap();
+        if !shared.unsubscribed {
+            self.patterns.remove(&shared.subject);
+        }
+        !shared.unsubscribed
+    }
+
+    /// Marks `server` as unsubscribed whilst its subscribers still receive what is in flight;
+    /// new subscribers to its subject get a server subscription of their own.
+    /// Returns whether the server still has to be told.
+    pub fn unsubscribe(&mut self, server: &str) -> bool {
+        let Some(shared) = self.shared.get_mut(server) else {
+            return false;
+        };
+        if std::mem::replace(&mut shared.unsubscribed, true) {
+            return false;
+        }
+
+        self.patterns.remove(&shared.subject);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{matches, Multiplexer, SubjectTrie};
+
+    #[test]
+    fn trie() {
+        let mut trie = SubjectTrie::default();
+        assert_eq!(trie.insert("orders.*.created", 1), None);
+        assert_eq!(trie.insert("orders.>", 2), None);
+        assert_eq!(trie.insert("orders.*.created", 3), Some(1));
+        assert_eq!(trie.insert("orders.eu.created", 4), None);
+
+        assert_eq!(trie.matches("orders.eu.created"), [&2, &3, &4]);
+        assert_eq!(trie.matches("orders.*.created"), [&2, &3]);
+        assert_eq!(trie.matches("orders.eu.>"), [&2]);
+        assert_eq!(trie.matches("orders.>"), [&2]);
+        assert!(trie.matches("orders").is_empty());
+        assert!(trie.matches(">").is_empty());
+
+        assert_eq!(trie.remove("orders.*.created"), Some(3));
+        assert_eq!(trie.remove("orders.*.created"), None);
+        assert_eq!(trie.matches("orders.eu.created"), [&2, &4]);
+        assert_eq!(trie.remove("orders.eu.created"), Some(4));
+        assert_eq!(trie.remove("orders.>"), Some(2));
+        assert!(trie.children.is_empty());
+    }
+
+    #[test]
+    fn subject_matches() {
+        assert!(matches("orders.eu", "orders.eu"));
+        assert!(matches("orders.*", "orders.eu"));
+        assert!(matches("orders.>", "orders.eu.created"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(!matches("orders.*", "orders.eu.created"));
+        assert!(!matches("orders.eu.created", "orders.eu"));
+        assert!(!matches("orders.us", "orders.eu"));
+    }
+
+    #[test]
+    fn shared_until_last_subscriber_leaves() {
+        let mut multiplexer = Multiplexer::default();
+        let next_sid = || "server".to_string();
+
+        assert_eq!(
+            multiplexer.attach("updates", "1".into(), next_sid),
+            ("server".into(), true)
+        );
+        assert_eq!(
+            multiplexer.attach("updates", "2".into(), || unreachable!()),
+            ("server".into(), false)
+        );
+        assert_eq!(
+            multiplexer.subscribers("server"),
+            Some(["1".to_string(), "2".to_string()].as_slice())
+        );
+
+        assert!(!multiplexer.detach("server", "1"));
+        assert!(multiplexer.detach("server", "2"));
+        assert_eq!(multiplexer.subscribers("server"), None);
+
+        // Joined by subjects it matches, but not by broader ones
+        assert_eq!(
+            multiplexer.attach("orders.>", "5".into(), || "orders".into()),
+            ("orders".into(), true)
+        );
+        assert_eq!(
+            multiplexer.attach("orders.*.created", "6".into(), || unreachable!()),
+            ("orders".into(), false)
+        );
+        assert_eq!(
+            multiplexer.attach("orders", "7".into(), || "exact".into()),
+            ("exact".into(), true)
+        );
+        assert_eq!(multiplexer.subject("orders"), Some("orders.>"));
+
+        // Once unsubscribed, nobody may join anymore
+        multiplexer.attach("updates", "3".into(), || "drained".into());
+        assert!(multiplexer.unsubscribe("drained"));
+        assert!(!multiplexer.unsubscribe("drained"));
+        assert_eq!(
+            multiplexer.attach("updates", "4".into(), || "fresh".into()),
+            ("fresh".into(), true)
+        );
+        assert!(!multiplexer.detach("drained", "3"));
+    }
+}
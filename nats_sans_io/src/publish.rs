@@ -0,0 +1,78 @@
+use bytes::Bytes;
+use nats_codec::{ClientCommand, HeaderMap};
+
+use crate::Sid;
+
+#[derive(Debug, Default, Clone)]
+pub struct PublishOptions {
+    /// Subject that receivers should reply to.
+    pub reply_to: Option<String>,
+    /// Sent with `HPUB` instead of `PUB`; requires a server that supports headers.
+    pub headers: Option<HeaderMap>,
+    /// Await the server's `+OK`, see [Event::Acknowledged](crate::Event::Acknowledged).
+    /// Requires [ConnectOptions::verbose](crate::ConnectOptions::verbose).
+    pub acknowledge: bool,
+}
+
+/// Why a command was rejected, returned by [NatsBinding::handle_client_input](crate::NatsBinding::handle_client_input).
+/// Nothing is sent, but the connection carries on.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    #[error("Server does not support headers")]
+    HeadersNotSupported,
+
+    #[error("Acknowledgements require verbose mode")]
+    NotVerbose,
+
+    #[error("Message of {size} bytes exceeds the maximum payload of {max_payload} bytes")]
+    MaxPayloadExceeded { size: usize, max_payload: usize },
+
+    #[error("Connection is being drained")]
+    Draining,
+
+    #[error("Connection is closed")]
+    Closed,
+
+    #[error("Too much is buffered until the connection is established")]
+    BacklogFull,
+
+    #[error("Subscription {0} is unknown or already ending")]
+    UnknownSubscription(Sid),
+}
+
+/// Validates a publish against what the server announced in `INFO`.
+pub fn validate(
+    headers: Option<&HeaderMap>,
+    payload: &Bytes,
+    info: &nats_codec::Info,
+) -> Result<(), CommandError> {
+    if headers.is_some() && !info.headers {
+        return Err(CommandError::HeadersNotSupported);
+    }
+
+    // The server counts the headers towards the payload, and closes the connection if it is exceeded
+    let size = payload.len() + headers.map_or(0, HeaderMap::encoded_len);
+    if size > info.max_payload {
+        return Err(CommandError::MaxPayloadExceeded {
+            size,
+            max_payload: info.max_payload,
+        });
+    }
+
+    Ok(())
+}
+
+/// `HPUB` if there are headers, `PUB` otherwise.
+pub fn command(
+    subject: String,
+    reply_to: Option<String>,
+    headers: Option<HeaderMap>,
+    payload: Bytes,
+) -> ClientCommand {
+    match headers {
+        Some(headers) => ClientCommand::HPublish(nats_codec::HPublish::new(
+            subject, reply_to, headers, payload,
+        )),
+        None => ClientCommand::Publish(nats_codec::Publish::new(subject, reply_to, payload)),
+    }
+}
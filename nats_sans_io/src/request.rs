@@ -0,0 +1,116 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Instant,
+};
+
+use crate::{CommandError, IdGenerator};
+
+/// Status sent by the server in place of a reply when nobody is subscribed to the subject.
+const NO_RESPONDERS: u16 = 503;
+
+/// Identifies a request towards [NatsBinding::poll_reply](crate::NatsBinding::poll_reply).
+pub type RequestId = u64;
+
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    #[error("No responders are subscribed to the subject")]
+    NoResponders,
+
+    #[error("No reply was received in time")]
+    TimedOut,
+
+    #[error("Request was rejected: {0}")]
+    Rejected(#[from] CommandError),
+}
+
+pub type RequestResponse = Result<nats_codec::Message, RequestError>;
+
+/// Routes replies arriving on a single wildcard subscription, `_INBOX.<nuid>.*`,
+/// to the requests awaiting them.
+#[derive(Debug, Default)]
+pub struct Requests {
+    /// `_INBOX.<nuid>`; each request replies to its ID appended to it.
+    pub inbox: Option<String>,
+    /// SID of the inbox subscription, once it has been created.
+    pub inbox_sid: Option<String>,
+    id_generator: RequestId,
+    /// Deadlines of the requests awaiting a reply.
+    pub pending: HashMap<RequestId, Instant>,
+    /// Outcomes of requests that have not been polled yet.
+    pub replies: VecDeque<(RequestId, RequestResponse)>,
+}
+
+impl Requests {
+    /// Returns the inbox prefix, generating it on first use.
+    pub fn inbox(&mut self, ids: &mut dyn IdGenerator) -> &str {
+        self.inbox
+            .get_or_insert_with(|| format!("_INBOX.{}", ids.next_nuid()))
+    }
+
+    /// Registers a request, returning its ID and the subject its reply should be sent to.
+    pub fn insert(&mut self, deadline: Instant, ids: &mut dyn IdGenerator) -> (RequestId, String) {
+        self.id_generator += 1;
+        let id = self.id_generator;
+        let reply_to = format!("{}.{id}", self.inbox(ids));
+
+        self.pending.insert(id, deadline);
+        (id, reply_to)
+    }
+
+    /// Forgets about the pending request whose reply is sent to `subject`, returning its ID.
+    fn remove(&mut self, subject: &str) -> Option<RequestId> {
+        self.inbox
+            .as_deref()
+            .and_then(|inbox| subject.strip_prefix(inbox))
+            .and_then(|suffix| suffix.strip_prefix('.'))
+            .and_then(|id| id.parse().ok())
+            .filter(|id| self.pending.remove(id).is_some())
+    }
+
+    /// Resolves the request `message` replies to.
+    pub fn reply(&mut self, message: nats_codec::Message) {
+        let Some(id) = self.remove(&message.subject) else {
+            log::debug!("Discarding reply to unknown request: {}", message.subject);
+            return;
+        };
+
+        let response = if message.headers.status() == Some(NO_RESPONDERS) {
+            Err(RequestError::NoResponders)
+        } else {
+            Ok(message)
+        };
+        self.replies.push_back((id, response));
+    }
+
+    /// Fails the request whose reply is sent to `reply_to`, as it will not be published after all.
+    /// Returns whether there was such a request.
+    pub fn reject(&mut self, reply_to: &str, error: CommandError) -> bool {
+        let Some(id) = self.remove(reply_to) else {
+            return false;
+        };
+        self.replies
+            .push_back((id, Err(RequestError::Rejected(error))));
+        true
+    }
+
+    /// Earliest deadline of all pending requests.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().min().copied()
+    }
+
+    /// Fails every request whose deadline has passed.
+    pub fn expire(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            self.pending.remove(&id);
+            log::debug!("Request {id} timed out");
+            self.replies.push_back((id, Err(RequestError::TimedOut)));
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use std::{fmt, net::SocketAddr, str::FromStr};
+
+use rand::{seq::SliceRandom, Rng};
+
+/// Port used by NATS servers unless specified otherwise.
+pub const DEFAULT_PORT: u16 = 4222;
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ServerAddr {
+    pub host: String,
+    pub port: u16,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum ServerAddrError {
+    #[error("Host is missing")]
+    MissingHost,
+
+    #[error("IPv6 address is not terminated by `]`")]
+    UnterminatedIpv6,
+
+    #[error("Port is not a number between 0 and 65535")]
+    BadPort,
+}
+
+impl FromStr for ServerAddr {
+    type Err = ServerAddrError;
+
+    /// Accepts `nats://host:port`, `host:port`, `[ipv6]:port` and `host`, defaulting to [DEFAULT_PORT].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix("nats://").unwrap_or(s);
+
+        let (host, port) = match s.strip_prefix('[') {
+            Some(ipv6) => {
+                let Some((host, rest)) = ipv6.split_once(']') else {
+                    return Err(ServerAddrError::UnterminatedIpv6);
+                };
+                (host, rest.strip_prefix(':'))
+            }
+            None => match s.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (s, None),
+            },
+        };
+
+        if host.is_empty() {
+            return Err(ServerAddrError::MissingHost);
+        }
+
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| ServerAddrError::BadPort)?,
+            None => DEFAULT_PORT,
+        };
+
+        Ok(Self {
+            host: host.into(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for ServerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl From<SocketAddr> for ServerAddr {
+    fn from(value: SocketAddr) -> Self {
+        Self {
+            host: value.ip().to_string(),
+            port: value.port(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Server {
+    pub addr: ServerAddr,
+    /// How many consecutive attempts to connect to this server have failed.
+    pub failures: usize,
+    /// Whether the server was announced by the cluster via `connect_urls` instead of given by the user.
+    pub discovered: bool,
+}
+
+/// The servers a binding may connect to, in the order they are tried.
+#[derive(Debug, Default)]
+pub struct ServerPool {
+    servers: Vec<Server>,
+    /// The server most recently selected by [Self::next].
+    current: Option<ServerAddr>,
+}
+
+impl ServerPool {
+    /// Adds `servers` in a random order, skipping those already known.
+    pub fn extend(
+        &mut self,
+        servers: impl IntoIterator<Item = ServerAddr>,
+        discovered: bool,
+        rng: &mut impl Rng,
+    ) {
+        let mut unknown: Vec<ServerAddr> = vec![];
+        for addr in servers {
+            if !self.contains(&addr) && !unknown.contains(&addr) {
+                unknown.push(addr);
+            }
+        }
+        unknown.shuffle(rng);
+
+        self.servers.extend(unknown.into_iter().map(|addr| Server {
+            addr,
+            failures: 0,
+            discovered,
+        }));
+    }
+
+    /// Applies the `connect_urls` announced in `INFO`:
+    /// unknown servers are added, discovered servers that are no longer announced are removed.
+    pub fn discover(&mut self, connect_urls: &[String], rng: &mut impl Rng) {
+        let announced: Vec<ServerAddr> = connect_urls
+            .iter()
+            .filter_map(|url| match url.parse() {
+                Ok(addr) => Some(addr),
+                Err(e) => {
+                    log::warn!("Ignoring malformed URL {url} in `connect_urls`: {e}");
+                    None
+                }
+            })
+            .collect();
+
+        let current = self.current.as_ref();
+        self.servers.retain(|server| {
+            !server.discovered || Some(&server.addr) == current || announced.contains(&server.addr)
+        });
+
+        self.extend(announced, true, rng);
+    }
+
+    pub fn contains(&self, addr: &ServerAddr) -> bool {
+        self.servers.iter().any(|server| &server.addr == addr)
+    }
+
+    pub fn servers(&self) -> &[Server] {
+        &self.servers
+    }
+
+    pub fn current(&self) -> Option<&Server> {
+        let current = self.current.as_ref()?;
+        self.servers.iter().find(|server| &server.addr == current)
+    }
+
+    pub fn current_mut(&mut self) -> Option<&mut Server> {
+        let current = self.current.as_ref()?;
+        self.servers
+            .iter_mut()
+            .find(|server| &server.addr == current)
+    }
+
+    /// Whether any server has failed fewer than `max_failures` times.
+    pub fn has_candidate(&self, max_failures: usize) -> bool {
+        self.servers
+            .iter()
+            .any(|server| server.failures < max_failures)
+    }
+
+    /// Selects the server following the current one that has failed fewer than `max_failures` times.
+    pub fn next(&mut self, max_failures: usize) -> Option<ServerAddr> {
+        let start = self
+            .current
+            .as_ref()
+            .and_then(|current| self.servers.iter().position(|s| &s.addr == current))
+            .map_or(0, |position| position + 1);
+
+        let len = self.servers.len();
+        let next = (0..len)
+            .map(|offset| &self.servers[(start + offset) % len])
+            .find(|server| server.failures < max_failures)?
+            .addr
+            .clone();
+
+        self.current = Some(next.clone());
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::{ServerAddr, ServerAddrError, ServerPool, DEFAULT_PORT};
+
+    fn addr(host: &str, port: u16) -> ServerAddr {
+        ServerAddr {
+            host: host.into(),
+            port,
+        }
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            "nats://demo.nats.io:4443".parse(),
+            Ok(addr("demo.nats.io", 4443))
+        );
+        assert_eq!("10.0.0.1:4222".parse(), Ok(addr("10.0.0.1", 4222)));
+        assert_eq!("[::1]:4333".parse(), Ok(addr("::1", 4333)));
+        assert_eq!("localhost".parse(), Ok(addr("localhost", DEFAULT_PORT)));
+
+        assert_eq!("".parse::<ServerAddr>(), Err(ServerAddrError::MissingHost));
+        assert_eq!(
+            "[::1:4222".parse::<ServerAddr>(),
+            Err(ServerAddrError::UnterminatedIpv6)
+        );
+        assert_eq!(
+            "localhost:http".parse::<ServerAddr>(),
+            Err(ServerAddrError::BadPort)
+        );
+    }
+
+    #[test]
+    fn rotation_skips_failed_servers() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut pool = ServerPool::default();
+        pool.extend([addr("a", 1), addr("b", 2), addr("c", 3)], false, &mut rng);
+
+        let first = pool.next(1).unwrap();
+        pool.current_mut().unwrap().failures += 1;
+
+        let second = pool.next(1).unwrap();
+        assert_ne!(first, second);
+
+        let third = pool.next(1).unwrap();
+        assert_ne!(third, first);
+        assert_ne!(third, second);
+
+        // Wraps around, skipping the first server as it failed
+        assert_eq!(pool.next(1), Some(second));
+
+        // Once every server has failed, there is nothing left to try
+        for _ in 0..3 {
+            pool.next(usize::MAX);
+            pool.current_mut().unwrap().failures = 1;
+        }
+        assert!(!pool.has_candidate(1));
+        assert_eq!(pool.next(1), None);
+    }
+
+    #[test]
+    fn discovery() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut pool = ServerPool::default();
+        pool.extend([addr("seed", 4222)], false, &mut rng);
+
+        pool.discover(&["a:4222".into(), "b:4222".into()], &mut rng);
+        assert_eq!(pool.servers().len(), 3);
+
+        // `b` has left the cluster, `c` has joined; the seed is retained regardless
+        pool.discover(&["a:4222".into(), "c:4222".into()], &mut rng);
+        let mut hosts: Vec<_> = pool
+            .servers()
+            .iter()
+            .map(|s| s.addr.host.as_str())
+            .collect();
+        hosts.sort();
+        assert_eq!(hosts, ["a", "c", "seed"]);
+    }
+}
@@ -0,0 +1,462 @@
+//! Drives a [NatsBinding] against a scripted fake server on a virtual clock,
+//! so that timing-dependent behaviour can be tested deterministically.
+
+use std::{
+    collections::HashMap,
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+use nats_codec::{ClientCommand, ServerCommand};
+
+use crate::{Event, NatsBinding};
+
+/// How often the binding and the server may exchange commands without the clock advancing.
+const MAX_STEPS: usize = 10_000;
+
+pub struct Simulation {
+    pub binding: NatsBinding,
+    /// Virtual clock; only moves when told to.
+    pub now: Instant,
+    pub server: FakeServer,
+    /// Commands transmitted by the binding whilst connected, in the order the server received them.
+    pub transmitted: Vec<ClientCommand>,
+    /// Events emitted by the binding, in order.
+    pub events: Vec<Event>,
+}
+
+/// Answers `PING`s, routes publishes to subscriptions and acknowledges commands in verbose mode,
+/// unless told otherwise.
+pub struct FakeServer {
+    pub info: Box<nats_codec::Info>,
+    /// How long each command takes to reach the client; those of the client arrive right away.
+    pub latency: Duration,
+    /// How many of the next `PING`s are left unanswered.
+    pub drop_pongs: usize,
+    /// How many of the next connection attempts fail.
+    pub refuse_connections: usize,
+    connected: bool,
+    verbose: bool,
+    /// Commands on their way to the client, with the time they arrive at.
+    in_flight: Vec<(Instant, ServerCommand)>,
+    subscriptions: HashMap<String, (String, Option<NonZeroUsize>)>,
+}
+
+impl Simulation {
+    /// The transport to the server is established right away; the server sends `info` after its latency.
+    pub fn new(mut binding: NatsBinding, info: Box<nats_codec::Info>) -> Self {
+        let now = Instant::now();
+        binding.handle_connected(now);
+
+        let mut simulation = Self {
+            binding,
+            now,
+            server: FakeServer {
+                info,
+                latency: Duration::ZERO,
+                drop_pongs: 0,
+                refuse_connections: 0,
+                connected: false,
+                verbose: false,
+                in_flight: vec![],
+                subscriptions: HashMap::new(),
+            },
+            transmitted: vec![],
+            events: vec![],
+        };
+        simulation.server.connect(now);
+        simulation.settle();
+        simulation
+    }
+
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.server.latency = latency;
+        self.server.in_flight = std::mem::take(&mut self.server.in_flight)
+            .into_iter()
+            .map(|(_, command)| (self.now + latency, command))
+            .collect();
+        self
+    }
+
+    /// Sends `command` to the client, arriving after the server's latency.
+    pub fn send(&mut self, command: ServerCommand) {
+        self.send_after(self.server.latency, command);
+    }
+
+    /// Sends `command` to the client, arriving after `delay` regardless of the server's latency,
+    /// e.g. to have it overtake commands that were sent before.
+    pub fn send_after(&mut self, delay: Duration, command: ServerCommand) {
+        self.server.send_at(self.now + delay, command);
+    }
+
+    /// The transport breaks down; whatever is in flight is lost.
+    pub fn disconnect(&mut self) {
+        self.server.disconnect();
+        self.binding.handle_disconnected(self.now);
+        self.settle();
+    }
+
+    /// Advances the clock by `duration`, delivering whatever arrives and handling the binding's timeouts
+    /// in the order they are due.
+    pub fn advance(&mut self, duration: Duration) {
+        let until = self.now + duration;
+        for _ in 0..MAX_STEPS {
+            self.settle();
+
+            let next = [self.binding.poll_timeout(), self.server.next_arrival()]
+                .into_iter()
+                .flatten()
+                .min()
+                .filter(|next| *next <= until);
+            let Some(next) = next else {
+                self.now = until;
+                return;
+            };
+
+            self.now = self.now.max(next);
+            // Giving up on the connection is reported by events as well
+            while let Some(command) = self.server.arrived(self.now) {
+                let _ = self.binding.handle_server_input(command, self.now);
+                self.settle();
+            }
+            let _ = self.binding.handle_timeout(self.now);
+        }
+        panic!("Simulation did not settle within {MAX_STEPS} steps");
+    }
+
+    /// Removes what was transmitted so far.
+    pub fn take_transmitted(&mut self) -> Vec<ClientCommand> {
+        std::mem::take(&mut self.transmitted)
+    }
+
+    /// Removes the events emitted so far.
+    pub fn take_events(&mut self) -> Vec<Event> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Exchanges commands between binding and server until neither has anything left to say.
+    fn settle(&mut self) {
+        for _ in 0..MAX_STEPS {
+            if let Some(command) = self.binding.poll_transmit() {
+                if self.server.connected {
+                    self.server.receive(&command, self.now);
+                    self.transmitted.push(command);
+                }
+                continue;
+            }
+
+            let Some(event) = self.binding.poll_event() else {
+                return;
+            };
+            match &event {
+                Event::Disconnected | Event::Closed => self.server.disconnect(),
+                Event::Reconnect { .. } if self.server.refuse_connections > 0 => {
+                    self.server.refuse_connections -= 1;
+                    self.binding.handle_connect_failed(self.now);
+                }
+                Event::Reconnect { .. } => {
+                    self.binding.handle_connected(self.now);
+                    self.server.connect(self.now);
+                }
+                _ => {}
+            }
+            self.events.push(event);
+        }
+        panic!("Simulation did not settle within {MAX_STEPS} steps");
+    }
+}
+
+impl FakeServer {
+    fn connect(&mut self, now: Instant) {
+        self.connected = true;
+        self.verbose = false;
+        self.subscriptions.clear();
+        self.send_at(now + self.latency, ServerCommand::Info(self.info.clone()));
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+        self.in_flight.clear();
+    }
+
+    fn send_at(&mut self, at: Instant, command: ServerCommand) {
+        // Stable, so that commands arriving at the same time keep the order they were sent in
+        let position = self.in_flight.partition_point(|(other, _)| *other <= at);
+        self.in_flight.insert(position, (at, command));
+    }
+
+    fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.first().map(|(at, _)| *at)
+    }
+
+    fn arrived(&mut self, now: Instant) -> Option<ServerCommand> {
+        if self.next_arrival()? > now {
+            return None;
+        }
+        Some(self.in_flight.remove(0).1)
+    }
+
+    fn receive(&mut self, command: &ClientCommand, now: Instant) {
+        let reply_at = now + self.latency;
+        match command {
+            ClientCommand::Connect(connect) => self.verbose = connect.verbose,
+            ClientCommand::Ping if self.drop_pongs > 0 => self.drop_pongs -= 1,
+            ClientCommand::Ping => self.send_at(reply_at, ServerCommand::Pong),
+            ClientCommand::Pong => {}
+            ClientCommand::Subscribe(subscribe) => {
+                self.subscriptions
+                    .insert(subscribe.sid.clone(), (subscribe.subject.clone(), None));
+            }
+            ClientCommand::Unsubscribe(unsubscribe) => match unsubscribe.max_msgs {
+                Some(max_msgs) => {
+                    if let Some((_, limit)) = self.subscriptions.get_mut(&unsubscribe.sid) {
+                        *limit = Some(max_msgs);
+                    }
+                }
+                None => {
+                    self.subscriptions.remove(&unsubscribe.sid);
+                }
+            },
+            ClientCommand::Publish(publish) => {
+                for sid in self.route(&publish.subject) {
+                    let msg = nats_codec::Msg {
+                        subject: publish.subject.clone(),
+                        sid,
+                        reply_to: publish.reply_to.clone(),
+                        bytes: publish.bytes,
+                        payload: publish.payload.clone(),
+                    };
+                    self.send_at(reply_at, ServerCommand::Msg(msg));
+                }
+            }
+            ClientCommand::HPublish(publish) => {
+                for sid in self.route(&publish.subject) {
+                    let msg = nats_codec::HMsg {
+                        subject: publish.subject.clone(),
+                        sid,
+                        reply_to: publish.reply_to.clone(),
+                        header_bytes: publish.header_bytes,
+                        total_bytes: publish.total_bytes,
+                        headers: publish.headers.clone(),
+                        payload: publish.payload.clone(),
+                    };
+                    self.send_at(reply_at, ServerCommand::HMsg(msg));
+                }
+            }
+        }
+
+        let acknowledged = !matches!(command, ClientCommand::Ping | ClientCommand::Pong);
+        if self.verbose && acknowledged {
+            self.send_at(reply_at, ServerCommand::Ok);
+        }
+    }
+
+    /// SIDs of the subscriptions matching `subject`, counting towards their `max_msgs`.
+    fn route(&mut self, subject: &str) -> Vec<String> {
+        let mut sids: Vec<String> = self
+            .subscriptions
+            .iter()
+            .filter(|(_, (pattern, _))| matches(pattern, subject))
+            .map(|(sid, _)| sid.clone())
+            .collect();
+        sids.sort();
+
+        for sid in &sids {
+            let Some((_, Some(limit))) = self.subscriptions.get_mut(sid) else {
+                continue;
+            };
+            match NonZeroUsize::new(limit.get() - 1) {
+                Some(remaining) => *limit = remaining,
+                None => {
+                    self.subscriptions.remove(sid);
+                }
+            }
+        }
+        sids
+    }
+}
+
+/// Whether `subject` matches `pattern`, which may contain the wildcards `*` and `>`.
+fn matches(pattern: &str, subject: &str) -> bool {
+    let mut subject = subject.split('.');
+    for token in pattern.split('.') {
+        match (token, subject.next()) {
+            (">", Some(_)) => return true,
+            ("*", Some(_)) => {}
+            (token, Some(other)) if token == other => {}
+            _ => return false,
+        }
+    }
+    subject.next().is_none()
+}
+
+mod tests {
+    use std::{num::NonZeroUsize, time::Duration};
+
+    use bytes::Bytes;
+    use nats_codec::{ClientCommand, ServerCommand};
+
+    use super::{matches, Simulation};
+    use crate::{
+        info, ConnectionCommand, Event, NatsBinding, PublishOptions, ReconnectOptions,
+        SubscriptionOptions, Timeouts,
+    };
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        ping_interval: Duration::from_secs(2),
+        pong_delay: Duration::ZERO,
+        keep_alive: Duration::from_secs(5),
+    };
+
+    fn reconnecting() -> NatsBinding {
+        let mut binding = NatsBinding::new(TIMEOUTS).with_reconnect(ReconnectOptions {
+            max_attempts: NonZeroUsize::new(3),
+            backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(1),
+            jitter: Duration::ZERO,
+            ..Default::default()
+        });
+        binding.add_server("localhost".parse().unwrap());
+        binding
+    }
+
+    fn subscribe(simulation: &mut Simulation, subject: &str) -> String {
+        let options = SubscriptionOptions::default();
+        crate::subscribe(&mut simulation.binding, subject, options, simulation.now)
+    }
+
+    fn publish(simulation: &mut Simulation, subject: &str) {
+        simulation
+            .binding
+            .handle_client_input(
+                ConnectionCommand::Publish {
+                    subject: subject.into(),
+                    payload: Bytes::from_static(b"Hello World!"),
+                    options: PublishOptions::default(),
+                },
+                simulation.now,
+            )
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("orders.*.created", "orders.eu.created"));
+        assert!(matches("orders.>", "orders.eu.created"));
+        assert!(!matches("orders.>", "orders"));
+        assert!(!matches("orders.*", "orders.eu.created"));
+        assert!(!matches("orders.eu", "orders.us"));
+    }
+
+    #[test]
+    fn latency_is_measured() {
+        let latency = Duration::from_millis(50);
+        let mut simulation =
+            Simulation::new(NatsBinding::new(TIMEOUTS), info()).with_latency(latency);
+
+        // Nothing is sent before `INFO` arrives
+        assert!(simulation.take_transmitted().is_empty());
+        simulation.advance(latency);
+        assert!(matches!(
+            simulation.take_transmitted()[..],
+            [ClientCommand::Connect(_), ClientCommand::Ping]
+        ));
+
+        // Established once the `PONG` arrives, which took a round trip
+        simulation.advance(latency);
+        assert_eq!(simulation.take_events(), [Event::Connected]);
+        assert_eq!(simulation.binding.rtt(), Some(latency));
+    }
+
+    #[test]
+    fn keep_alive_lost_and_reconnected() {
+        let mut simulation = Simulation::new(reconnecting(), info());
+        let sid = subscribe(&mut simulation, "updates");
+        simulation.advance(Duration::ZERO);
+        assert_eq!(simulation.take_events(), [Event::Connected]);
+
+        // The server stops answering, so the connection is given up on once keep-alive expires
+        simulation.server.drop_pongs = usize::MAX;
+        simulation.advance(TIMEOUTS.keep_alive - Duration::from_millis(1));
+        assert!(simulation.take_events().is_empty());
+        simulation.advance(Duration::from_millis(1));
+        assert_eq!(simulation.take_events(), [Event::Disconnected]);
+
+        // One attempt is refused, the next one succeeds and replays the subscription
+        simulation.server.drop_pongs = 0;
+        simulation.server.refuse_connections = 1;
+        simulation.take_transmitted();
+        simulation.advance(Duration::from_secs(2));
+        assert!(matches!(
+            simulation.take_events()[..],
+            [
+                Event::Reconnect { attempt: 1, .. },
+                Event::Reconnect { attempt: 2, .. },
+                Event::Connected
+            ]
+        ));
+        assert!(simulation
+            .take_transmitted()
+            .iter()
+            .any(|transmit| matches!(
+                transmit,
+                ClientCommand::Subscribe(subscribe) if subscribe.sid == sid
+            )));
+
+        // Messages are routed to it again
+        publish(&mut simulation, "updates");
+        simulation.advance(Duration::ZERO);
+        assert!(matches!(simulation.binding.poll_message(), Some((s, _)) if s == sid));
+    }
+
+    #[test]
+    fn reordering_and_errors() {
+        let mut simulation = Simulation::new(NatsBinding::new(TIMEOUTS), info());
+        let sid = subscribe(&mut simulation, "updates");
+        simulation.advance(Duration::ZERO);
+        assert_eq!(simulation.take_events(), [Event::Connected]);
+
+        let msg = |payload: &'static str| {
+            ServerCommand::Msg(nats_codec::Msg {
+                subject: "updates".into(),
+                sid: sid.clone(),
+                reply_to: None,
+                bytes: payload.len(),
+                payload: Bytes::from_static(payload.as_bytes()),
+            })
+        };
+        simulation.send_after(Duration::from_millis(20), msg("first"));
+        simulation.send_after(Duration::from_millis(10), msg("second"));
+        simulation.send(ServerCommand::Err("Authorization Violation".into()));
+        simulation.advance(Duration::from_millis(20));
+
+        let received: Vec<Bytes> =
+            std::iter::from_fn(|| simulation.binding.poll_message().map(|(_, m)| m.payload))
+                .collect();
+        assert_eq!(
+            received,
+            [Bytes::from_static(b"second"), Bytes::from_static(b"first")]
+        );
+        assert!(matches!(
+            simulation.take_events()[..],
+            [Event::AuthorizationFailed { .. }]
+        ));
+    }
+
+    #[test]
+    fn disconnect_loses_what_is_in_flight() {
+        let mut simulation =
+            Simulation::new(reconnecting(), info()).with_latency(Duration::from_millis(10));
+        simulation.advance(Duration::from_millis(20));
+        assert_eq!(simulation.take_events(), [Event::Connected]);
+        subscribe(&mut simulation, "updates");
+        publish(&mut simulation, "updates");
+        simulation.advance(Duration::from_millis(5));
+
+        simulation.disconnect();
+        assert_eq!(simulation.take_events(), [Event::Disconnected]);
+        simulation.advance(Duration::from_secs(1));
+        assert!(simulation.binding.poll_message().is_none());
+    }
+}
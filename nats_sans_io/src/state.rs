@@ -1,42 +1,703 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::atomic::{AtomicU64, Ordering},
-    time::Instant,
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroUsize,
+    time::{Duration, Instant},
 };
 
 use nats_codec::{ClientCommand, Connect, ServerCommand};
-use tokio::sync::mpsc;
+use rand::{rngs::StdRng, SeedableRng};
 
-use crate::{subscription::SubscribeResponse, ConnectionCommand};
+use crate::{
+    ack::{AckError, AckId, Acks},
+    id::{IdGenerator, NuidGenerator},
+    multiplex::{self, Multiplexer},
+    publish::{self, CommandError, PublishOptions},
+    request::{RequestId, Requests},
+    server_pool::ServerPool,
+    statistics::Statistics,
+    subscription::{Sid, SlowConsumerPolicy, SubscribeResponse, Subscription, SubscriptionOptions},
+    ConnectOptions, ConnectionCommand, Event, FlushId, HandshakeOptions,
+};
 
 #[derive(Debug)]
 pub enum ConnState {
     /// First message sent from a server to the client must be [INFO](nats_codec::Info).
     AwaitingInfo(AwaitingInfo),
-    /// The first message received was [INFO](nats_codec::Info); connection has now been established.
+    /// The server sent [INFO](nats_codec::Info), and is yet to accept `CONNECT`.
+    AwaitingPong(AwaitingPong),
+    /// The server accepted `CONNECT`; connection has now been established.
     InfoReceived(InfoReceived),
     /// The first message received was not [INFO](nats_codec::Info),
     NotInfoReceived,
 
+    /// There is no connection; a new one is being established.
+    Reconnecting(Reconnecting),
+
     // Server is not responding to `PINGs`
     ConnectionLost,
+
+    /// The connection was closed on purpose, e.g. once it was drained.
+    Closed(Closed),
+}
+
+/// State that is shared by every connection made by a binding,
+/// i.e. it survives reconnecting to a server.
+#[derive(Debug)]
+pub struct Session {
+    /// Chooses SIDs and inbox names.
+    pub ids: Box<dyn IdGenerator>,
+    pub subscriptions: HashMap<Sid, Subscription>,
+    /// Messages delivered to subscribers that have not been polled yet, each numbered in the order
+    /// it arrived. Only subscriptions that have messages waiting have an entry.
+    pub messages: HashMap<Sid, VecDeque<(u64, nats_codec::Message)>>,
+    /// How many messages were queued for subscribers so far.
+    pub arrivals: u64,
+    /// How many consecutive reconnection attempts have been made without receiving `INFO`.
+    pub reconnect_attempts: usize,
+    pub servers: ServerPool,
+    pub requests: Requests,
+    pub flush_id_generator: FlushId,
+    /// Flushes whose `PONG` completes a drain.
+    pub drains: HashMap<FlushId, Drain>,
+    /// Set once the whole connection is being drained.
+    pub draining: Option<ConnectionDrain>,
+    pub ack_id_generator: AckId,
+    pub connect: ConnectOptions,
+    pub events: VecDeque<Event>,
+    pub rng: StdRng,
+    /// Does not cover the subscriptions, which keep track of themselves.
+    pub statistics: Statistics,
+    /// Whether a connection has ever been established.
+    pub connected_before: bool,
+    /// `None` unless subscriptions of the same subject share a server subscription.
+    pub multiplexer: Option<Multiplexer>,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            ids: Box::new(NuidGenerator::new()),
+            subscriptions: HashMap::new(),
+            messages: HashMap::new(),
+            arrivals: 0,
+            reconnect_attempts: 0,
+            servers: ServerPool::default(),
+            requests: Requests::default(),
+            flush_id_generator: 0,
+            drains: HashMap::new(),
+            draining: None,
+            ack_id_generator: 0,
+            connect: ConnectOptions::default(),
+            events: VecDeque::new(),
+            rng: StdRng::from_entropy(),
+            statistics: Statistics::default(),
+            connected_before: false,
+            multiplexer: None,
+        }
+    }
+}
+
+impl Session {
+    /// Queues `message` for its subscriber, or for each subscriber that shares its server subscription
+    /// and whose subject matches the message's.
+    fn deliver(
+        &mut self,
+        message: nats_codec::Message,
+        buffered_transmits: &mut VecDeque<ClientCommand>,
+    ) {
+        let Some(subscribers) = self
+            .multiplexer
+            .as_ref()
+            .and_then(|multiplexer| multiplexer.subscribers(&message.sid))
+        else {
+            self.deliver_to(message, buffered_transmits);
+            return;
+        };
+
+        let subscribers: Vec<Sid> = subscribers
+            .iter()
+            .filter(|sid| {
+                self.subscriptions.get(*sid).is_some_and(|subscription| {
+                    multiplex::matches(&subscription.subject, &message.subject)
+                })
+            })
+            .cloned()
+            .collect();
+        for sid in subscribers {
+            let message = nats_codec::Message {
+                sid,
+                ..message.clone()
+            };
+            self.deliver_to(message, buffered_transmits);
+        }
+    }
+
+    /// Queues `message` for the subscriber of its SID, applying the subscriber's
+    /// [PendingLimits](crate::PendingLimits). Ends the subscription once it has received `max_msgs`.
+    fn deliver_to(
+        &mut self,
+        message: nats_codec::Message,
+        buffered_transmits: &mut VecDeque<ClientCommand>,
+    ) {
+        let sid = message.sid.clone();
+        let Some(subscription) = self.subscriptions.get_mut(&sid) else {
+            log::warn!("Subscriber with SID {sid} is unknown");
+            return;
+        };
+        subscription.delivered += 1;
+
+        let size = message.payload.len();
+        let fits_at_all = subscription.limits.messages > 0 && size <= subscription.limits.bytes;
+        let slow = if subscription.fits(size) {
+            false
+        } else if subscription.limits.policy == SlowConsumerPolicy::DropOldest && fits_at_all {
+            let pending = self.messages.entry(sid.clone()).or_default();
+            while !subscription.fits(size) {
+                let Some((_, oldest)) = pending.pop_front() else {
+                    break;
+                };
+                subscription.pending_messages -= 1;
+                subscription.pending_bytes -= oldest.payload.len();
+                subscription.dropped += 1;
+            }
+            true
+        } else {
+            subscription.dropped += 1;
+            true
+        };
+
+        if subscription.fits(size) {
+            subscription.pending_messages += 1;
+            subscription.pending_bytes += size;
+            self.arrivals += 1;
+            self.messages
+                .entry(sid.clone())
+                .or_default()
+                .push_back((self.arrivals, message));
+        }
+
+        // Only notify when the subscriber starts falling behind, not for every discarded message
+        if slow && !subscription.slow {
+            log::warn!("Subscriber {sid} is a slow consumer");
+            self.events.push_back(Event::SlowConsumer {
+                sid: sid.clone(),
+                dropped: subscription.dropped,
+            });
+        }
+        subscription.slow = slow;
+
+        let unsubscribe = if slow && subscription.limits.policy == SlowConsumerPolicy::Error {
+            Some(sid.clone())
+        } else if subscription.remaining() == Some(0) {
+            // The server has already unsubscribed, as it was told `max_msgs` along with `SUB`
+            log::debug!("Subscription {sid} has received all of its messages");
+            None
+        } else {
+            return;
+        };
+        let unsubscribe = match self.subscriptions.remove(&sid).and_then(|s| s.shared) {
+            Some(server) => self.release(&server, &sid),
+            None => unsubscribe,
+        };
+        if let Some(sid) = unsubscribe {
+            buffered_transmits.push_back(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+                sid,
+                max_msgs: None,
+            }));
+        }
+        self.events.push_back(Event::Unsubscribed { sid });
+    }
+
+    /// Forgets about the subscription `sid`, either right away or once it has received `max_msgs`.
+    /// Returns what the server has to be told, if anything.
+    fn unsubscribe(
+        &mut self,
+        sid: &str,
+        max_msgs: Option<NonZeroUsize>,
+    ) -> Option<nats_codec::Unsubscribe> {
+        let unsubscribe = nats_codec::Unsubscribe {
+            sid: sid.into(),
+            max_msgs,
+        };
+        let Some(subscription) = self.subscriptions.get_mut(sid) else {
+            log::warn!("Cannot unsubscribe {sid} because it is unknown");
+            return Some(unsubscribe);
+        };
+
+        match (max_msgs, &subscription.shared) {
+            // Counted by the client, as the server subscription is shared with others
+            (Some(max_msgs), Some(_)) if subscription.delivered < max_msgs.get() => {
+                subscription.max_msgs = Some(max_msgs);
+                None
+            }
+            (Some(max_msgs), None) if subscription.delivered < max_msgs.get() => {
+                subscription.max_msgs = Some(max_msgs);
+                Some(unsubscribe)
+            }
+            (_, Some(server)) => {
+                let server = server.clone();
+                self.subscriptions.remove(sid);
+                let sid = self.release(&server, sid)?;
+                Some(nats_codec::Unsubscribe {
+                    sid,
+                    max_msgs: None,
+                })
+            }
+            (_, None) => {
+                self.subscriptions.remove(sid);
+                Some(unsubscribe)
+            }
+        }
+    }
+
+    /// Forgets about the subscription `sid` and tells the driver so.
+    fn end_subscription(&mut self, sid: &str, buffered_transmits: &mut VecDeque<ClientCommand>) {
+        let Some(subscription) = self.subscriptions.remove(sid) else {
+            return;
+        };
+        if let Some(server) = subscription
+            .shared
+            .and_then(|server| self.release(&server, sid))
+        {
+            buffered_transmits.push_back(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+                sid: server,
+                max_msgs: None,
+            }));
+        }
+        self.events
+            .push_back(Event::Unsubscribed { sid: sid.into() });
+    }
+
+    /// Detaches the removed subscription `sid` from the server subscription it shared with others.
+    /// Returns the server subscription once nobody is left to receive its messages.
+    fn release(&mut self, server: &str, sid: &str) -> Option<Sid> {
+        let multiplexer = self.multiplexer.as_mut()?;
+        multiplexer.detach(server, sid).then(|| server.into())
+    }
+
+    /// Marks `sid` as draining, returning the subscription the server has to be told to unsubscribe.
+    /// A shared server subscription is only unsubscribed once each of its subscribers is draining.
+    fn start_draining(&mut self, sid: &str) -> Option<Sid> {
+        let subscription = self.subscriptions.get_mut(sid)?;
+        subscription.draining = true;
+        let Some(server) = subscription.shared.clone() else {
+            return Some(sid.into());
+        };
+
+        let Session {
+            subscriptions,
+            multiplexer,
+            ..
+        } = self;
+        let multiplexer = multiplexer.as_mut()?;
+        let drained = multiplexer
+            .subscribers(&server)?
+            .iter()
+            .all(|sid| subscriptions.get(sid).is_none_or(|s| s.draining));
+        (drained && multiplexer.unsubscribe(&server)).then_some(server)
+    }
+
+    fn next_flush_id(&mut self) -> FlushId {
+        self.flush_id_generator += 1;
+        self.flush_id_generator
+    }
+
+    /// Tells whoever issued `command`, which was accepted before, that it is not sent after all.
+    /// Requests fail with [RequestError::Rejected](crate::RequestError::Rejected).
+    pub fn discard(&mut self, command: ConnectionCommand, error: CommandError) {
+        log::error!("Discarding {command:?}: {error}");
+        let ConnectionCommand::Publish {
+            subject, options, ..
+        } = command
+        else {
+            return;
+        };
+        let rejected = options
+            .reply_to
+            .is_some_and(|reply_to| self.requests.reject(&reply_to, error.clone()));
+        if !rejected {
+            self.events
+                .push_back(Event::PublishDiscarded { subject, error });
+        }
+    }
+
+    /// Like [Self::discard], for whatever was buffered until connected.
+    pub fn discard_backlog(&mut self, backlog: Preliminary, error: CommandError) {
+        match backlog {
+            Preliminary::Command(command) => self.discard(command, error),
+            Preliminary::Acknowledged(id, command) => {
+                log::error!("Discarding {command:?}: {error}");
+                self.events.push_back(Event::Acknowledged {
+                    id,
+                    result: Err(AckError::Discarded),
+                });
+            }
+            Preliminary::Flush(_) | Preliminary::Subscription(_) => {}
+        }
+    }
+
+    /// Removes the oldest message that has not been polled yet.
+    pub fn poll_message(&mut self) -> Option<(Sid, nats_codec::Message)> {
+        let (sid, _) = self
+            .messages
+            .iter()
+            .min_by_key(|(_, pending)| pending.front().map(|(arrival, _)| *arrival))?;
+        let sid = sid.clone();
+        let message = self.poll_message_for(&sid)?;
+        Some((sid, message))
+    }
+
+    /// Removes the oldest message of `sid` that has not been polled yet.
+    pub fn poll_message_for(&mut self, sid: &str) -> Option<nats_codec::Message> {
+        let pending = self.messages.get_mut(sid)?;
+        let (_, message) = pending.pop_front()?;
+        if pending.is_empty() {
+            self.messages.remove(sid);
+        }
+        if let Some(subscription) = self.subscriptions.get_mut(sid) {
+            subscription.pending_messages -= 1;
+            subscription.pending_bytes -= message.payload.len();
+        }
+        Some(message)
+    }
+}
+
+/// What completes once the `PONG` to the `PING` of a drain arrives, see [ConnectionCommand::Drain].
+#[derive(Debug)]
+pub enum Drain {
+    /// The server will not send any further messages to the subscription.
+    Subscription(Sid),
+    /// The next stage of [Session::draining] has been reached.
+    Connection,
+}
+
+/// Stages of draining the whole connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionDrain {
+    /// Messages that are in flight are still delivered; nothing new may be subscribed to.
+    Subscriptions,
+    /// Publishes issued so far are flushed; nothing new may be published.
+    Publishes,
+}
+
+/// Issued before the connection was established; replayed once it is.
+#[derive(Debug)]
+pub enum Preliminary {
+    Command(ConnectionCommand),
+    /// A subscription that was registered with the [Session] whilst not connected.
+    Subscription(Sid),
+    /// A [ConnectionCommand::Flush] that was issued, or not yet answered, whilst not connected.
+    Flush(FlushId),
+    /// A publish whose acknowledgement is awaited, see [PublishOptions::acknowledge].
+    Acknowledged(AckId, ConnectionCommand),
 }
 
 #[derive(Debug)]
 pub struct AwaitingInfo {
-    pub preliminary: Vec<(ConnectionCommand, Instant)>,
+    pub preliminary: Vec<Preliminary>,
+    /// Payload of the publishes in [Self::preliminary].
+    pub buffered_bytes: usize,
+    /// When the connection is given up on unless the server has sent `INFO`.
+    pub deadline: Option<Instant>,
+}
+
+/// `CONNECT` and `PING` were sent in reply to `INFO`; the connection is established
+/// once the server answers with `PONG`, or rejected if it answers with `-ERR`.
+#[derive(Debug)]
+pub struct AwaitingPong {
+    pub info: Box<nats_codec::Info>,
+    /// `CONNECT` and `PING`, unless transmitted already.
+    pub buffered_transmits: VecDeque<nats_codec::ClientCommand>,
+    /// Replayed once the connection is established.
+    pub preliminary: Vec<Preliminary>,
+    /// Payload of the publishes in [Self::preliminary].
+    pub buffered_bytes: usize,
+    /// When the connection is given up on unless the server has answered the `PING`.
+    pub deadline: Option<Instant>,
+    /// When the `PING` was enqueued, to measure the round-trip time.
+    pub pinged_at: Instant,
+    /// What the server answered with `-ERR`, see [Event::ConnectRejected].
+    pub rejected: Option<String>,
+}
+
+impl AwaitingPong {
+    /// Replays what was issued whilst not connected, and takes up keeping the connection alive.
+    fn establish(&mut self, session: &mut Session, now: Instant) -> ConnState {
+        let preliminary = std::mem::take(&mut self.preliminary);
+        // Whatever the handshake has not transmitted yet goes first
+        let mut buffered_transmits = std::mem::take(&mut self.buffered_transmits);
+
+        session.reconnect_attempts = 0;
+        if session.connected_before {
+            session.statistics.reconnects += 1;
+        }
+        session.connected_before = true;
+        if let Some(server) = session.servers.current_mut() {
+            server.failures = 0;
+        }
+        if let Some(connect_urls) = &self.info.connect_urls {
+            session.servers.discover(connect_urls, &mut session.rng);
+        }
+
+        // Subscriptions made on a previous connection are unknown to this server.
+        // Those made whilst not connected are sent in the order they were issued.
+        let registered: HashSet<&Sid> = preliminary
+            .iter()
+            .filter_map(|backlog| match backlog {
+                Preliminary::Subscription(sid) => Some(sid),
+                Preliminary::Command(_) | Preliminary::Flush(_) | Preliminary::Acknowledged(..) => {
+                    None
+                }
+            })
+            .collect();
+        // Shared server subscriptions are sent once, no matter how many subscribers they have
+        let mut replayed: HashSet<Sid> = HashSet::new();
+        let mut exhausted = vec![];
+        // Those replaced whilst the previous server was a lame duck have served their purpose
+        let mut replaced = vec![];
+        session.subscriptions.retain(|sid, subscription| {
+            if subscription.replaced {
+                replaced.push(sid.clone());
+                return true;
+            }
+            if subscription.remaining() == Some(0) {
+                log::debug!("Dropping exhausted subscription {sid}");
+                exhausted.extend(
+                    subscription
+                        .shared
+                        .clone()
+                        .map(|server| (server, sid.clone())),
+                );
+                return false;
+            }
+            // Whatever is being drained is not worth subscribing to again
+            if !registered.contains(sid)
+                && !subscription.draining
+                && subscription
+                    .shared
+                    .as_ref()
+                    .is_none_or(|server| replayed.insert(server.clone()))
+            {
+                buffered_transmits
+                    .extend(subscription.transmits(sid, session.multiplexer.as_ref()));
+            }
+            true
+        });
+        // The new server has not learnt about them yet, so there is nothing to unsubscribe
+        for (server, sid) in exhausted {
+            session.release(&server, &sid);
+        }
+        if let (Some(inbox), Some(sid)) = (&session.requests.inbox, &session.requests.inbox_sid) {
+            buffered_transmits.push_back(ClientCommand::Subscribe(nats_codec::Subscribe {
+                subject: format!("{inbox}.*"),
+                queue_group: None,
+                sid: sid.clone(),
+            }));
+        }
+
+        // The handshake has already measured the round-trip time
+        let rtt = now.duration_since(self.pinged_at);
+        session.statistics.rtt = Some(rtt);
+        session.events.push_back(Event::Connected);
+        for sid in replaced {
+            // This server never learnt about them, so there is nothing to unsubscribe
+            session.end_subscription(&sid, &mut VecDeque::new());
+        }
+
+        let s = ConnState::InfoReceived(InfoReceived {
+            info: self.info.clone(),
+            buffered_transmits,
+            keep_alive: KeepAliveState {
+                established_at: Some(now),
+                last_ping_sent_at: Some(self.pinged_at),
+                last_pong_received_at: Some(now),
+                rtt: Some(rtt),
+                ..Default::default()
+            },
+            acks: Acks::new(session.connect.verbose),
+            lame_duck: false,
+            migrate_at: None,
+        });
+
+        preliminary.into_iter().fold(s, |mut s, backlog| {
+            let ConnState::InfoReceived(inner) = &mut s else {
+                return s;
+            };
+            match backlog {
+                Preliminary::Subscription(sid) => {
+                    if let Some(subscription) =
+                        session.subscriptions.get(&sid).filter(|subscription| {
+                            !subscription.draining
+                                && subscription
+                                    .shared
+                                    .as_ref()
+                                    .is_none_or(|server| replayed.insert(server.clone()))
+                        })
+                    {
+                        inner
+                            .buffered_transmits
+                            .extend(subscription.transmits(&sid, session.multiplexer.as_ref()));
+                    }
+                    s
+                }
+                Preliminary::Flush(id) => {
+                    inner.ping(Some(id), now);
+                    s
+                }
+                Preliminary::Command(command) => {
+                    if let Err(e) = inner.validate(&command) {
+                        session.discard(command, e);
+                        return s;
+                    }
+                    s.step(session, command, now).unwrap_or(s)
+                }
+                Preliminary::Acknowledged(id, command) => {
+                    if let Err(e) = inner.validate(&command) {
+                        log::error!("Discarding {command:?}: {e}");
+                        session.events.push_back(Event::Acknowledged {
+                            id,
+                            result: Err(e.into()),
+                        });
+                        return s;
+                    }
+                    s.acknowledged(session, id, command, now);
+                    s
+                }
+            }
+        })
+    }
+}
+
+/// Introduces the client to the server.
+fn connect(session: &Session) -> ClientCommand {
+    ClientCommand::Connect(Connect {
+        // From INFO
+        sig: None,
+        nkey: None,
+        verbose: session.connect.verbose,
+        // Hardcoded
+        pedantic: true,
+        tls_required: false,
+        auth_token: None,
+        user: None,
+        lang: "Rust".into(),
+        name: None,
+        pass: None,
+        version: "1.0".into(),
+        // Servers only send asynchronous `INFO`, e.g. of lame duck mode, to clients that speak version 1
+        protocol: Some(1),
+        echo: Some(!session.connect.no_echo),
+        jwt: None,
+        no_responders: Some(session.connect.no_responders),
+        headers: Some(true),
+    })
 }
 
 #[derive(Debug)]
 pub struct InfoReceived {
+    pub info: Box<nats_codec::Info>,
     pub buffered_transmits: VecDeque<nats_codec::ClientCommand>,
     pub keep_alive: KeepAliveState,
-    pub sid_generator: AtomicU64,
-    pub sid2subscriber: HashMap<String, mpsc::Sender<nats_codec::Message>>,
+    pub acks: Acks,
+    /// Whether the server announced that it is about to shut down, see [Event::LameDuck].
+    pub lame_duck: bool,
+    /// When to move on to another server, once the server is a lame duck.
+    pub migrate_at: Option<Instant>,
+}
+
+impl InfoReceived {
+    /// Enqueues a `PING`, on behalf of the flush `id` if given.
+    pub fn ping(&mut self, flush: Option<FlushId>, now: Instant) {
+        self.buffered_transmits.push_back(ClientCommand::Ping);
+        self.keep_alive.outstanding_pings.push_back((now, flush));
+    }
+
+    /// Advances draining the connection once the server has caught up with its current stage.
+    fn drained(&mut self, session: &mut Session, now: Instant) -> Option<ConnState> {
+        match session.draining {
+            Some(ConnectionDrain::Subscriptions) => {
+                log::info!("Subscriptions drained, flushing publishes");
+                let sids: Vec<Sid> = session.subscriptions.keys().cloned().collect();
+                for sid in sids {
+                    session.end_subscription(&sid, &mut self.buffered_transmits);
+                }
+
+                session.draining = Some(ConnectionDrain::Publishes);
+                let id = session.next_flush_id();
+                self.ping(Some(id), now);
+                session.drains.insert(id, Drain::Connection);
+                None
+            }
+            Some(ConnectionDrain::Publishes) | None => {
+                log::info!("Connection drained");
+                Some(close(session, std::mem::take(&mut self.buffered_transmits)))
+            }
+        }
+    }
+
+    /// Applies what the server announced in an `INFO` sent after the connection was established.
+    fn merge(&mut self, update: nats_codec::Info) {
+        let info = &mut self.info;
+        info.max_payload = update.max_payload;
+        info.headers = update.headers;
+        info.ldm = update.ldm;
+        // Only announced when they change
+        if update.connect_urls.is_some() {
+            info.connect_urls = update.connect_urls;
+        }
+        if update.cluster.is_some() {
+            info.cluster = update.cluster;
+        }
+    }
+
+    /// Checks whether the server is able to handle `command`.
+    pub fn validate(&self, command: &ConnectionCommand) -> Result<(), CommandError> {
+        match command {
+            ConnectionCommand::Publish {
+                payload, options, ..
+            } => publish::validate(options.headers.as_ref(), payload, &self.info),
+            ConnectionCommand::Request {
+                payload, headers, ..
+            } => publish::validate(headers.as_ref(), payload, &self.info),
+            ConnectionCommand::Subscribe { .. }
+            | ConnectionCommand::Resubscribe { .. }
+            | ConnectionCommand::Unsubscribe { .. }
+            | ConnectionCommand::Flush
+            | ConnectionCommand::Drain { .. }
+            | ConnectionCommand::Close => Ok(()),
+        }
+    }
+}
+
+/// Whatever was enqueued before closing the connection is still transmitted.
+#[derive(Debug, Default)]
+pub struct Closed {
+    pub buffered_transmits: VecDeque<nats_codec::ClientCommand>,
+}
+
+/// Closes the connection on purpose, see [Event::Closed].
+fn close(session: &mut Session, buffered_transmits: VecDeque<ClientCommand>) -> ConnState {
+    session.events.push_back(Event::Closed);
+    ConnState::Closed(Closed { buffered_transmits })
+}
+
+#[derive(Debug)]
+pub struct Reconnecting {
+    /// When should the next attempt be made; `None` whilst an attempt is in progress.
+    pub reconnect_at: Option<Instant>,
+    /// Commands issued whilst disconnected, replayed once the next connection is established.
+    pub preliminary: Vec<Preliminary>,
+    /// Total size of the payloads of the publishes in [Self::preliminary].
+    pub buffered_bytes: usize,
+    /// Upper bound for [Self::buffered_bytes]; publishes exceeding it are discarded.
+    pub buffer_size: usize,
 }
 
 #[derive(Clone, Debug, Default)]
 pub struct KeepAliveState {
+    /// When was the connection established; the first `PING` is due right away.
+    pub established_at: Option<Instant>,
+
     /// Keep-Alive Client: when did the client last check that the server is still alive?
     pub last_ping_sent_at: Option<Instant>,
 
@@ -45,99 +706,161 @@ pub struct KeepAliveState {
 
     /// Keep-Alive Server: when did the server last indicate that it is still alive?
     pub last_pong_received_at: Option<Instant>,
+
+    /// `PING`s awaiting their `PONG`, oldest first, as the server answers them in order.
+    /// Those sent on behalf of [ConnectionCommand::Flush] carry its ID.
+    pub outstanding_pings: VecDeque<(Instant, Option<FlushId>)>,
+
+    /// Round-trip time measured by the most recent `PING`.
+    pub rtt: Option<Duration>,
+}
+
+/// Whether the server's `-ERR` is about the credentials, after which it closes the connection.
+pub fn is_authorization_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    [
+        "authorization violation",
+        "authentication timeout",
+        "authentication expired",
+        "authentication revoked",
+    ]
+    .iter()
+    .any(|reason| error.contains(reason))
 }
 
 pub trait Step<Command> {
-    fn step(&mut self, command: Command, now: Instant) -> Option<ConnState>;
+    fn step(&mut self, session: &mut Session, command: Command, now: Instant) -> Option<ConnState>;
 }
 
 impl Step<ServerCommand> for ConnState {
-    fn step(&mut self, command: ServerCommand, now: Instant) -> Option<ConnState> {
+    fn step(
+        &mut self,
+        session: &mut Session,
+        command: ServerCommand,
+        now: Instant,
+    ) -> Option<ConnState> {
         let new_state = match (self, command) {
-            (ConnState::AwaitingInfo(AwaitingInfo { preliminary }), ServerCommand::Info(_info)) => {
-                // https://doc.rust-lang.org/std/collections/struct.VecDeque.html#method.from
-                let preliminary = std::mem::take(preliminary);
-
-                let mut buffered_transmits: VecDeque<_> =
-                    VecDeque::with_capacity(preliminary.len());
-
-                buffered_transmits.push_front(ClientCommand::Connect(Connect {
-                    // From INFO
-                    sig: None,
-                    nkey: None,
-                    // Hardcoded
-                    verbose: true,
-                    pedantic: true,
-                    tls_required: false,
-                    auth_token: None,
-                    user: None,
-                    lang: "Rust".into(),
-                    name: None,
-                    pass: None,
-                    version: "1.0".into(),
-                    protocol: None,
-                    echo: None,
-                    jwt: None,
-                    no_responders: None,
-                    headers: Some(true),
-                }));
-
-                let s = ConnState::InfoReceived(InfoReceived {
-                    buffered_transmits,
-                    keep_alive: KeepAliveState {
-                        last_ping_sent_at: None,
-                        last_ping_received_at: None,
-                        last_pong_received_at: None,
-                    },
-                    sid_generator: AtomicU64::new(1),
-                    sid2subscriber: HashMap::new(),
-                });
-
-                let replayed = preliminary.into_iter().fold(s, |mut s, (backlog, _when)| {
-                    s.step(backlog, now).unwrap_or(s)
+            (
+                ConnState::AwaitingInfo(AwaitingInfo {
+                    preliminary,
+                    buffered_bytes,
+                    deadline,
+                }),
+                ServerCommand::Info(info),
+            ) => {
+                log::trace!("Received `INFO`, sending `CONNECT`");
+                Some(ConnState::AwaitingPong(AwaitingPong {
+                    info,
+                    buffered_transmits: VecDeque::from([connect(session), ClientCommand::Ping]),
+                    preliminary: std::mem::take(preliminary),
+                    buffered_bytes: *buffered_bytes,
+                    deadline: *deadline,
+                    pinged_at: now,
+                    rejected: None,
+                }))
+            }
+            (ConnState::AwaitingInfo { .. }, otherwise) => {
+                log::error!("Expected `INFO`, but received {otherwise:?}");
+                session.events.push_back(Event::ProtocolError {
+                    error: "Server did not start with INFO".into(),
                 });
+                Some(ConnState::NotInfoReceived)
+            }
 
-                Some(replayed)
+            // Handshake
+            (ConnState::AwaitingPong(awaiting), ServerCommand::Pong) => {
+                log::debug!("Server accepted `CONNECT`");
+                Some(awaiting.establish(session, now))
+            }
+            (ConnState::AwaitingPong(_), ServerCommand::Ok) => {
+                log::trace!("Received OK to `CONNECT`");
+                None
+            }
+            (ConnState::AwaitingPong(awaiting), ServerCommand::Err(error)) => {
+                log::error!("Server rejected `CONNECT`: {error}");
+                session.statistics.errors += 1;
+                awaiting.rejected = Some(error.clone());
+                if is_authorization_error(&error) {
+                    session.events.push_back(Event::AuthorizationFailed {
+                        error: error.clone(),
+                    });
+                }
+                session.events.push_back(Event::ConnectRejected { error });
+                None
+            }
+            (ConnState::AwaitingPong(awaiting), ServerCommand::Ping) => {
+                awaiting.buffered_transmits.push_back(ClientCommand::Pong);
+                None
+            }
+            (ConnState::AwaitingPong(awaiting), ServerCommand::Info(info)) => {
+                log::debug!("Received new `INFO` during handshake");
+                awaiting.info = info;
+                None
+            }
+            (ConnState::AwaitingPong(_), otherwise) => {
+                log::error!("Received {otherwise:?} before the server accepted `CONNECT`");
+                None
             }
-            (ConnState::AwaitingInfo { .. }, _otherwise) => Some(ConnState::NotInfoReceived),
 
             // Connection upheld
-            (ConnState::InfoReceived(_inner), ServerCommand::Ok) => {
+            (ConnState::InfoReceived(inner), ServerCommand::Ok) => {
                 log::trace!("Received OK");
+                if let Some(id) = inner.acks.answered() {
+                    session
+                        .events
+                        .push_back(Event::Acknowledged { id, result: Ok(()) });
+                }
                 None
             }
-            (ConnState::InfoReceived(_inner), ServerCommand::Err(error)) => {
+            (ConnState::InfoReceived(inner), ServerCommand::Err(error)) => {
                 log::error!("Received error!: {error}");
+                session.statistics.errors += 1;
+                if is_authorization_error(&error) {
+                    session.events.push_back(Event::AuthorizationFailed {
+                        error: error.clone(),
+                    });
+                }
+                if let Some(id) = inner.acks.answered() {
+                    session.events.push_back(Event::Acknowledged {
+                        id,
+                        result: Err(AckError::Server(error)),
+                    });
+                }
                 None
             }
-            (ConnState::InfoReceived(_inner), ServerCommand::Info(_info)) => {
-                log::warn!("Received new `INFO` during already established connection");
+            (ConnState::InfoReceived(inner), ServerCommand::Info(info)) => {
+                log::debug!("Received new `INFO` during already established connection");
+                if let Some(connect_urls) = &info.connect_urls {
+                    session.servers.discover(connect_urls, &mut session.rng);
+                }
+                if info.ldm == Some(true) && !inner.lame_duck {
+                    log::warn!("Server entered lame duck mode");
+                    inner.lame_duck = true;
+                    session.events.push_back(Event::LameDuck);
+                }
+                inner.merge(*info);
                 None
             }
-            (
-                ConnState::InfoReceived(InfoReceived { sid2subscriber, .. }),
-                ServerCommand::Msg(message),
-            ) => {
+            (ConnState::InfoReceived(inner), ServerCommand::Msg(message)) => {
                 log::trace!("Received message: {message:?}");
-                let Some(subscriber) = sid2subscriber.get(&message.sid) else {
-                    log::warn!("Subscriber with SID {} is unknown", message.sid);
+                session.statistics.received(&message.payload);
+                if session.requests.inbox_sid.as_ref() == Some(&message.sid) {
+                    session.requests.reply(message.into());
                     return None;
-                };
+                }
 
-                subscriber.try_send(message.into()).unwrap();
+                session.deliver(message.into(), &mut inner.buffered_transmits);
                 None
             }
-            (
-                ConnState::InfoReceived(InfoReceived { sid2subscriber, .. }),
-                ServerCommand::HMsg(message),
-            ) => {
+            (ConnState::InfoReceived(inner), ServerCommand::HMsg(message)) => {
                 log::trace!("Received message with headers: {message:?}");
-                let Some(subscriber) = sid2subscriber.get(&message.sid) else {
-                    log::warn!("Subscriber with SID {} is unknown", message.sid);
+                session.statistics.received(&message.payload);
+                if session.requests.inbox_sid.as_ref() == Some(&message.sid) {
+                    session.requests.reply(message.into());
                     return None;
-                };
+                }
 
-                subscriber.blocking_send(message.into()).unwrap();
+                session.deliver(message.into(), &mut inner.buffered_transmits);
                 None
             }
             (ConnState::InfoReceived(inner), ServerCommand::Ping) => {
@@ -147,99 +870,481 @@ impl Step<ServerCommand> for ConnState {
             }
             (ConnState::InfoReceived(inner), ServerCommand::Pong) => {
                 log::trace!("Received `PONG`");
-                inner.keep_alive.last_pong_received_at = Some(now);
+                let keep_alive = &mut inner.keep_alive;
+                keep_alive.last_pong_received_at = Some(now);
+
+                let Some((sent_at, flush)) = keep_alive.outstanding_pings.pop_front() else {
+                    log::warn!("Received `PONG` without having sent `PING`");
+                    return None;
+                };
+                let rtt = now.duration_since(sent_at);
+                keep_alive.rtt = Some(rtt);
+                session.statistics.rtt = Some(rtt);
+
+                // Only flushes are of interest beyond measuring the round-trip time
+                let id = flush?;
+                match session.drains.remove(&id) {
+                    None => session.events.push_back(Event::Flushed { id, rtt }),
+                    Some(Drain::Subscription(sid)) => {
+                        session.end_subscription(&sid, &mut inner.buffered_transmits)
+                    }
+                    Some(Drain::Connection) => return inner.drained(session, now),
+                }
                 None
             }
             (ConnState::NotInfoReceived, otherwise) => {
                 log::error!("Received {otherwise:?} despite not having connected!");
                 None
             }
+            (ConnState::Reconnecting(_), otherwise) => {
+                log::error!("Received {otherwise:?} whilst reconnecting!");
+                None
+            }
             (ConnState::ConnectionLost, otherwise) => {
                 log::error!("Received {otherwise:?} despite having lost the connection!");
                 None
             }
+            (ConnState::Closed(_), otherwise) => {
+                log::error!("Received {otherwise:?} despite having closed the connection!");
+                None
+            }
         };
 
         new_state
     }
 }
 
+impl ConnState {
+    /// Registers a subscription with the session. The server learns about it right away if connected,
+    /// otherwise once the next connection is established.
+    pub fn subscribe(
+        &mut self,
+        session: &mut Session,
+        subject: String,
+        options: SubscriptionOptions,
+    ) -> SubscribeResponse {
+        let Session {
+            ids, multiplexer, ..
+        } = session;
+        let sid = ids.next_sid();
+        let mut subscription = Subscription::new(subject, options);
+        let max_msgs = subscription.max_msgs;
+
+        // Members of a queue group each receive messages of their own, so they are never shared
+        let created = match multiplexer {
+            Some(multiplexer) if subscription.queue_group.is_none() => {
+                let (server, created) =
+                    multiplexer.attach(&subscription.subject, sid.clone(), || ids.next_sid());
+                subscription.shared = Some(server);
+                created
+            }
+            _ => true,
+        };
+
+        match self {
+            // Sent to the next server along with every other subscription, if there is one
+            ConnState::InfoReceived(InfoReceived {
+                lame_duck: true, ..
+            }) => {}
+            ConnState::InfoReceived(InfoReceived {
+                buffered_transmits, ..
+            }) => {
+                if created {
+                    buffered_transmits.extend(subscription.transmits(&sid, multiplexer.as_ref()));
+                }
+            }
+            ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+            | ConnState::AwaitingPong(AwaitingPong { preliminary, .. })
+            | ConnState::Reconnecting(Reconnecting { preliminary, .. }) => {
+                preliminary.push(Preliminary::Subscription(sid.clone()));
+            }
+            ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_) => {
+                log::error!("Subscription {sid} will not receive anything; connection is gone");
+            }
+        }
+        session.subscriptions.insert(sid.clone(), subscription);
+
+        SubscribeResponse { sid, max_msgs }
+    }
+
+    /// Subscribes anew, then drains `sid`, so that the server starts sending to the new subscription
+    /// before it stops sending to the old one. A lame duck is not told about the new subscription,
+    /// so it keeps sending to the old one until the connection ends.
+    pub fn resubscribe(
+        &mut self,
+        session: &mut Session,
+        sid: Sid,
+        subject: String,
+        options: SubscriptionOptions,
+        now: Instant,
+    ) -> SubscribeResponse {
+        let response = self.subscribe(session, subject, options);
+        match self {
+            ConnState::InfoReceived(InfoReceived {
+                lame_duck: true, ..
+            }) => {
+                if let Some(subscription) = session.subscriptions.get_mut(&sid) {
+                    subscription.replaced = true;
+                }
+            }
+            _ => self.drain(session, Some(sid), now),
+        }
+        response
+    }
+
+    /// Fails if `command` would have to be buffered until connected, but exceeds the backlog's `limits`.
+    pub fn admit(
+        &self,
+        command: &ConnectionCommand,
+        limits: &HandshakeOptions,
+    ) -> Result<(), CommandError> {
+        let (preliminary, buffered_bytes, max_bytes) = match self {
+            ConnState::AwaitingInfo(AwaitingInfo {
+                preliminary,
+                buffered_bytes,
+                ..
+            })
+            | ConnState::AwaitingPong(AwaitingPong {
+                preliminary,
+                buffered_bytes,
+                ..
+            }) => (preliminary, *buffered_bytes, limits.max_backlog_bytes),
+            ConnState::Reconnecting(Reconnecting {
+                preliminary,
+                buffered_bytes,
+                buffer_size,
+                ..
+            }) => (
+                preliminary,
+                *buffered_bytes,
+                limits.max_backlog_bytes.min(*buffer_size),
+            ),
+            _ => return Ok(()),
+        };
+
+        let size = match command {
+            ConnectionCommand::Publish { payload, .. }
+            | ConnectionCommand::Request { payload, .. } => payload.len(),
+            ConnectionCommand::Subscribe { .. }
+            | ConnectionCommand::Resubscribe { .. }
+            | ConnectionCommand::Flush => 0,
+            // These shrink the backlog, or do away with it
+            ConnectionCommand::Unsubscribe { .. }
+            | ConnectionCommand::Drain { .. }
+            | ConnectionCommand::Close => return Ok(()),
+        };
+
+        if preliminary.len() >= limits.max_backlog || buffered_bytes + size > max_bytes {
+            return Err(CommandError::BacklogFull);
+        }
+        Ok(())
+    }
+
+    /// Enqueues a `PING` whose `PONG` confirms that the server has processed everything sent before,
+    /// see [Event::Flushed]. Unless connected, it is sent once the next connection is established.
+    pub fn flush(&mut self, session: &mut Session, now: Instant) -> FlushId {
+        let id = session.next_flush_id();
+
+        match self {
+            ConnState::InfoReceived(inner) => inner.ping(Some(id), now),
+            ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+            | ConnState::AwaitingPong(AwaitingPong { preliminary, .. })
+            | ConnState::Reconnecting(Reconnecting { preliminary, .. }) => {
+                preliminary.push(Preliminary::Flush(id));
+            }
+            ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_) => {
+                log::error!("Flush {id} will not complete; connection is gone");
+            }
+        }
+        id
+    }
+
+    /// Publishes a request with a unique reply subject, whose reply is returned by
+    /// [NatsBinding::poll_reply](crate::NatsBinding::poll_reply).
+    pub fn request(
+        &mut self,
+        session: &mut Session,
+        subject: String,
+        payload: bytes::Bytes,
+        headers: Option<nats_codec::HeaderMap>,
+        timeout: Duration,
+        now: Instant,
+    ) -> RequestId {
+        let Session { ids, requests, .. } = session;
+
+        // All replies are received by a single subscription, created on demand.
+        // Unless connected to a server that is not a lame duck, it is sent along with
+        // the other subscriptions once the next connection is established.
+        if requests.inbox_sid.is_none() {
+            let sid = ids.next_sid();
+            let subject = format!("{}.*", requests.inbox(ids.as_mut()));
+            if let ConnState::InfoReceived(InfoReceived {
+                buffered_transmits,
+                lame_duck: false,
+                ..
+            }) = self
+            {
+                buffered_transmits.push_back(ClientCommand::Subscribe(nats_codec::Subscribe {
+                    subject,
+                    queue_group: None,
+                    sid: sid.clone(),
+                }));
+            }
+            requests.inbox_sid = Some(sid);
+        }
+
+        let (id, reply_to) = requests.insert(now + timeout, ids.as_mut());
+        let publish = ConnectionCommand::Publish {
+            subject,
+            payload,
+            options: PublishOptions {
+                reply_to: Some(reply_to),
+                headers,
+                ..Default::default()
+            },
+        };
+        if let Some(change) = self.step(session, publish, now) {
+            *self = change;
+        }
+        id
+    }
+
+    /// Publishes `command`, whose acknowledgement by the server is signalled by [Event::Acknowledged].
+    /// Unless connected, it is sent once the next connection is established.
+    pub fn publish_acknowledged(
+        &mut self,
+        session: &mut Session,
+        command: ConnectionCommand,
+        now: Instant,
+    ) -> AckId {
+        session.ack_id_generator += 1;
+        let id = session.ack_id_generator;
+        self.acknowledged(session, id, command, now);
+        id
+    }
+
+    /// Steps `command`, and tags whatever it enqueued as awaiting acknowledgement `id`.
+    fn acknowledged(
+        &mut self,
+        session: &mut Session,
+        id: AckId,
+        command: ConnectionCommand,
+        now: Instant,
+    ) {
+        let queued = |s: &ConnState| match s {
+            ConnState::InfoReceived(inner) => inner.buffered_transmits.len(),
+            ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+            | ConnState::AwaitingPong(AwaitingPong { preliminary, .. })
+            | ConnState::Reconnecting(Reconnecting { preliminary, .. }) => preliminary.len(),
+            ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_) => 0,
+        };
+
+        let before = queued(self);
+        if let Some(change) = self.step(session, command, now) {
+            *self = change;
+        }
+        if queued(self) == before {
+            session.events.push_back(Event::Acknowledged {
+                id,
+                result: Err(AckError::Discarded),
+            });
+            return;
+        }
+
+        match self {
+            ConnState::InfoReceived(inner) => inner.acks.tag(inner.buffered_transmits.len(), id),
+            ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+            | ConnState::AwaitingPong(AwaitingPong { preliminary, .. })
+            | ConnState::Reconnecting(Reconnecting { preliminary, .. }) => {
+                if let Some(Preliminary::Command(command)) = preliminary.pop() {
+                    preliminary.push(Preliminary::Acknowledged(id, command));
+                }
+            }
+            ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_) => {}
+        }
+    }
+
+    /// Unsubscribes `sid`, or every subscription if `None`, whilst still delivering the messages in flight.
+    /// Completion is confirmed by the `PONG` to a `PING` sent right after the `UNSUB`s, see [Drain].
+    pub fn drain(&mut self, session: &mut Session, sid: Option<Sid>, now: Instant) {
+        let (sids, drain) = match sid {
+            Some(sid) if session.subscriptions.contains_key(&sid) => {
+                (vec![sid.clone()], Drain::Subscription(sid))
+            }
+            Some(sid) => {
+                log::warn!("Cannot drain {sid} because it is unknown");
+                return;
+            }
+            None if session.draining.is_some() => {
+                log::debug!("Connection is already being drained");
+                return;
+            }
+            None => {
+                session.draining = Some(ConnectionDrain::Subscriptions);
+                let sids = session
+                    .subscriptions
+                    .iter()
+                    .filter(|(_, subscription)| !subscription.draining)
+                    .map(|(sid, _)| sid.clone())
+                    .collect();
+                (sids, Drain::Connection)
+            }
+        };
+
+        for sid in sids {
+            let Some(sid) = session.start_draining(&sid) else {
+                continue;
+            };
+            // Unless connected, the server does not know about the subscription anyway
+            if let ConnState::InfoReceived(InfoReceived {
+                buffered_transmits, ..
+            }) = self
+            {
+                buffered_transmits.push_back(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+                    sid,
+                    max_msgs: None,
+                }));
+            }
+        }
+
+        let id = self.flush(session, now);
+        session.drains.insert(id, drain);
+    }
+}
+
 impl Step<ConnectionCommand> for ConnState {
-    fn step(&mut self, command: ConnectionCommand, now: Instant) -> Option<ConnState> {
+    fn step(
+        &mut self,
+        session: &mut Session,
+        command: ConnectionCommand,
+        now: Instant,
+    ) -> Option<ConnState> {
         match (self, command) {
+            // Answered by [NatsBinding::handle_client_input]; the answer is of no interest when replaying
+            (s, ConnectionCommand::Subscribe { subject, options }) => {
+                s.subscribe(session, subject, options);
+            }
             (
-                ConnState::InfoReceived(InfoReceived {
-                    buffered_transmits,
-                    sid_generator,
-                    sid2subscriber,
-                    ..
-                }),
-                ConnectionCommand::Subscribe {
+                s,
+                ConnectionCommand::Resubscribe {
+                    sid,
                     subject,
                     options,
-                    sender,
                 },
             ) => {
-                let sid = sid_generator.fetch_add(1, Ordering::Relaxed);
-                let (sub_send, sub_recv) = mpsc::channel(1024);
-
-                sid2subscriber.insert(sid.to_string(), sub_send);
-
-                sender
-                    .send(SubscribeResponse {
-                        sid: sid.to_string(),
-                        msg_chan: sub_recv,
-                        max_msgs: options.max_msgs,
-                    })
-                    .unwrap();
-
-                buffered_transmits.push_back(ClientCommand::Subscribe(nats_codec::Subscribe {
-                    subject,
-                    queue_group: options.queue_group,
-                    sid: sid.to_string(),
-                }));
-                if let Some(max_msgs) = options.max_msgs {
-                    buffered_transmits.push_back(ClientCommand::Unsubscribe(
-                        nats_codec::Unsubscribe {
-                            sid: sid.to_string(),
-                            max_msgs: Some(max_msgs),
-                        },
-                    ))
+                s.resubscribe(session, sid, subject, options, now);
+            }
+            (s, ConnectionCommand::Flush) => {
+                s.flush(session, now);
+            }
+            (s, ConnectionCommand::Drain { sid }) => {
+                s.drain(session, sid, now);
+            }
+            (ConnState::Closed(_), ConnectionCommand::Close) => {
+                log::debug!("Connection is already closed");
+            }
+            (ConnState::InfoReceived(inner), ConnectionCommand::Close) => {
+                log::info!("Closing connection");
+                return Some(close(
+                    session,
+                    std::mem::take(&mut inner.buffered_transmits),
+                ));
+            }
+            // Nothing issued was sent to the server yet, so there is nothing to flush
+            (s, ConnectionCommand::Close) => {
+                log::info!("Closing connection before it was established");
+                if let ConnState::AwaitingInfo(AwaitingInfo { preliminary, .. })
+                | ConnState::AwaitingPong(AwaitingPong { preliminary, .. })
+                | ConnState::Reconnecting(Reconnecting { preliminary, .. }) = s
+                {
+                    for backlog in std::mem::take(preliminary) {
+                        session.discard_backlog(backlog, CommandError::Closed);
+                    }
                 }
+                return Some(close(session, VecDeque::new()));
+            }
+            (
+                s,
+                ConnectionCommand::Request {
+                    subject,
+                    payload,
+                    headers,
+                    timeout,
+                },
+            ) => {
+                s.request(session, subject, payload, headers, timeout, now);
             }
             (
                 ConnState::InfoReceived(InfoReceived {
-                    buffered_transmits,
-                    sid2subscriber,
-                    ..
+                    buffered_transmits, ..
                 }),
                 ConnectionCommand::Unsubscribe { sid, max_msgs },
             ) => {
-                let subscriber = sid2subscriber.remove(&sid);
-                if subscriber.is_none() {
-                    log::warn!("Cannot unsubscribe {sid} because it is unknown");
+                if let Some(unsubscribe) = session.unsubscribe(&sid, max_msgs) {
+                    buffered_transmits.push_back(ClientCommand::Unsubscribe(unsubscribe));
                 }
-                buffered_transmits.push_back(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
-                    sid,
-                    max_msgs,
-                }));
             }
             (
                 ConnState::InfoReceived(InfoReceived {
                     buffered_transmits, ..
                 }),
-                ConnectionCommand::Publish { subject, payload },
-            ) => buffered_transmits.push_back(ClientCommand::Publish(nats_codec::Publish {
+                ConnectionCommand::Publish {
+                    subject,
+                    payload,
+                    options,
+                },
+            ) => buffered_transmits.push_back(publish::command(
                 subject,
-                reply_to: None,
-                bytes: payload.len(),
+                options.reply_to,
+                options.headers,
                 payload,
-            })),
-            (ConnState::AwaitingInfo(AwaitingInfo { preliminary }), command) => {
-                preliminary.push((command, now));
+            )),
+            (
+                ConnState::AwaitingInfo(AwaitingInfo {
+                    preliminary,
+                    buffered_bytes,
+                    ..
+                })
+                | ConnState::AwaitingPong(AwaitingPong {
+                    preliminary,
+                    buffered_bytes,
+                    ..
+                }),
+                command,
+            ) => {
+                if let ConnectionCommand::Publish { payload, .. } = &command {
+                    *buffered_bytes += payload.len();
+                }
+                preliminary.push(Preliminary::Command(command));
             }
-            (ConnState::NotInfoReceived | ConnState::ConnectionLost, command) => {
-                log::error!("Discarding {command:?}; protocol error occurred");
+            // The server forgot about all subscriptions when the connection was lost,
+            // so there is nothing to send; the replay once reconnected takes care of it.
+            (ConnState::Reconnecting(_), ConnectionCommand::Unsubscribe { sid, max_msgs }) => {
+                session.unsubscribe(&sid, max_msgs);
+            }
+            (
+                ConnState::Reconnecting(Reconnecting {
+                    preliminary,
+                    buffered_bytes,
+                    buffer_size,
+                    ..
+                }),
+                command,
+            ) => {
+                if let ConnectionCommand::Publish { payload, .. } = &command {
+                    if *buffered_bytes + payload.len() > *buffer_size {
+                        session.discard(command, CommandError::BacklogFull);
+                        return None;
+                    }
+                    *buffered_bytes += payload.len();
+                }
+                preliminary.push(Preliminary::Command(command));
+            }
+            (
+                ConnState::NotInfoReceived | ConnState::ConnectionLost | ConnState::Closed(_),
+                command,
+            ) => {
+                log::error!("Discarding {command:?}; connection is gone");
             }
         }
 
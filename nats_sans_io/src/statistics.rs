@@ -0,0 +1,56 @@
+use std::{collections::HashMap, time::Duration};
+
+use nats_codec::ClientCommand;
+
+use crate::Sid;
+
+/// Counters maintained by a binding over its lifetime, across reconnects,
+/// see [NatsBinding::statistics](crate::NatsBinding::statistics).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Statistics {
+    /// Messages received from the server, including replies to requests.
+    pub in_msgs: u64,
+    /// Total size of the payloads of [Self::in_msgs].
+    pub in_bytes: u64,
+    /// Messages transmitted to the server.
+    pub out_msgs: u64,
+    /// Total size of the payloads of [Self::out_msgs].
+    pub out_bytes: u64,
+    /// How often a connection was re-established after the first one.
+    pub reconnects: u64,
+    /// How many `-ERR`s the server has sent.
+    pub errors: u64,
+    /// Round-trip time measured by the most recent `PING`.
+    pub rtt: Option<Duration>,
+    pub subscriptions: HashMap<Sid, SubscriptionStatistics>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SubscriptionStatistics {
+    pub subject: String,
+    /// How many messages the server has sent for the subscription.
+    pub delivered: usize,
+    /// How many messages were discarded because the subscriber did not keep up.
+    pub dropped: usize,
+    /// How many messages have not been polled yet.
+    pub pending_messages: usize,
+    /// Total size of the payloads of [Self::pending_messages].
+    pub pending_bytes: usize,
+}
+
+impl Statistics {
+    pub fn received(&mut self, payload: &[u8]) {
+        self.in_msgs += 1;
+        self.in_bytes += payload.len() as u64;
+    }
+
+    pub fn transmitted(&mut self, command: &ClientCommand) {
+        let payload = match command {
+            ClientCommand::Publish(publish) => &publish.payload,
+            ClientCommand::HPublish(publish) => &publish.payload,
+            _ => return,
+        };
+        self.out_msgs += 1;
+        self.out_bytes += payload.len() as u64;
+    }
+}
@@ -1,17 +1,143 @@
 use std::num::NonZeroUsize;
 
-use tokio::sync::mpsc;
+use nats_codec::ClientCommand;
+
+use crate::multiplex::Multiplexer;
+
+/// Subscription ID, chosen by the client to tell apart the messages of its subscriptions.
+pub type Sid = String;
 
 #[derive(Debug, Default, Clone)]
 pub struct SubscriptionOptions {
     pub max_msgs: Option<NonZeroUsize>,
     pub queue_group: Option<String>,
+    pub pending_limits: PendingLimits,
 }
 
-#[derive(Debug)]
+/// What happens to messages arriving for a subscriber that exceeds its [PendingLimits].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Discard the arriving message.
+    #[default]
+    DropNewest,
+    /// Discard the oldest pending messages to make room for the arriving one.
+    DropOldest,
+    /// Unsubscribe; the messages that are already pending are still delivered.
+    Error,
+}
+
+/// How much may be delivered to a subscriber without having been polled yet,
+/// see [NatsBinding::poll_message](crate::NatsBinding::poll_message).
+#[derive(Clone, Copy, Debug)]
+pub struct PendingLimits {
+    pub messages: usize,
+    /// Counts the payloads of the pending messages.
+    pub bytes: usize,
+    pub policy: SlowConsumerPolicy,
+}
+
+impl Default for PendingLimits {
+    /// Mirrors the defaults of the Go client.
+    fn default() -> Self {
+        Self {
+            messages: 512 * 1024,
+            bytes: 64 * 1024 * 1024,
+            policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SubscribeResponse {
-    pub sid: String,
+    pub sid: Sid,
     pub max_msgs: Option<NonZeroUsize>,
+}
+
+/// A subscription as known to the client; outlives the connection it was created on,
+/// so that it can be re-sent to the server after reconnecting.
+#[derive(Debug)]
+pub struct Subscription {
+    pub subject: String,
+    pub queue_group: Option<String>,
+    pub max_msgs: Option<NonZeroUsize>,
+    /// How many messages the server has sent for this subscription so far.
+    pub delivered: usize,
+    pub limits: PendingLimits,
+    /// How many of the messages delivered to the subscriber have not been polled yet.
+    pub pending_messages: usize,
+    /// Total size of the payloads of [Self::pending_messages].
+    pub pending_bytes: usize,
+    /// How many messages were discarded because the subscriber did not keep up.
+    pub dropped: usize,
+    /// Whether the subscriber is currently not keeping up; reset once a message is delivered again.
+    pub slow: bool,
+    /// Whether the subscription is being drained, so it is not sent to the server again.
+    pub draining: bool,
+    /// Whether the subscription was replaced whilst the server was a lame duck. It keeps receiving
+    /// messages until the connection ends, as its replacement is left to the next server.
+    pub replaced: bool,
+    /// Server subscription whose messages are fanned out to this one and others of the same subject,
+    /// see [NatsBinding::with_multiplexing](crate::NatsBinding::with_multiplexing).
+    pub shared: Option<Sid>,
+}
+
+impl Subscription {
+    pub fn new(subject: String, options: SubscriptionOptions) -> Self {
+        Self {
+            subject,
+            queue_group: options.queue_group,
+            max_msgs: options.max_msgs,
+            delivered: 0,
+            limits: options.pending_limits,
+            pending_messages: 0,
+            pending_bytes: 0,
+            dropped: 0,
+            slow: false,
+            draining: false,
+            replaced: false,
+            shared: None,
+        }
+    }
+
+    /// How many more messages the subscriber expects; `None` if it is unlimited.
+    /// `Some(0)` means the subscription is exhausted.
+    pub fn remaining(&self) -> Option<usize> {
+        self.max_msgs
+            .map(|max_msgs| max_msgs.get().saturating_sub(self.delivered))
+    }
+
+    /// Whether another message of `size` bytes stays within the limits.
+    pub fn fits(&self, size: usize) -> bool {
+        self.pending_messages < self.limits.messages
+            && self.pending_bytes + size <= self.limits.bytes
+    }
+
+    /// What makes the server aware of this subscription.
+    /// If it is [Self::shared], it is the server subscription of `multiplexer`,
+    /// and `max_msgs` is left to the client, as the others may want more.
+    pub fn transmits(&self, sid: &str, multiplexer: Option<&Multiplexer>) -> Vec<ClientCommand> {
+        if let Some(server) = &self.shared {
+            let subject = multiplexer
+                .and_then(|multiplexer| multiplexer.subject(server))
+                .unwrap_or(&self.subject);
+            return vec![ClientCommand::Subscribe(nats_codec::Subscribe {
+                subject: subject.into(),
+                queue_group: None,
+                sid: server.clone(),
+            })];
+        }
 
-    pub msg_chan: mpsc::Receiver<nats_codec::Message>,
+        let mut transmits = vec![ClientCommand::Subscribe(nats_codec::Subscribe {
+            subject: self.subject.clone(),
+            queue_group: self.queue_group.clone(),
+            sid: sid.into(),
+        })];
+        if let Some(max_msgs) = self.remaining().and_then(NonZeroUsize::new) {
+            transmits.push(ClientCommand::Unsubscribe(nats_codec::Unsubscribe {
+                sid: sid.into(),
+                max_msgs: Some(max_msgs),
+            }));
+        }
+        transmits
+    }
 }